@@ -3,6 +3,7 @@
 
 use std::collections::HashMap;
 use std::env;
+use std::ffi::OsString;
 pub static NIX_BIN: &str = env!("NIX_BIN");
 
 /// Explicitly set environment for nix calls
@@ -74,3 +75,22 @@ pub fn default_nix_subprocess_env() -> HashMap<String, String> {
 
     env_map
 }
+
+/// Select the `FLOX_*` variables among the `inherited` variable names
+/// that `keep` does not claim
+///
+/// Spawned processes should see the settings of the current flox instance,
+/// so these are not passed on.
+/// `inherited` is usually the names of [env::vars_os].
+pub(crate) fn stale_flox_vars(
+    inherited: impl IntoIterator<Item = OsString>,
+    keep: impl Fn(&str) -> bool,
+) -> Vec<OsString> {
+    inherited
+        .into_iter()
+        .filter(|var| {
+            let name = var.to_string_lossy();
+            name.starts_with("FLOX_") && !keep(&name)
+        })
+        .collect()
+}
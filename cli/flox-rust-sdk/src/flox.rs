@@ -1,5 +1,9 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
 use std::io::Read;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use derive_more::Constructor;
 use jsonwebtoken::{DecodingKey, Validation};
@@ -14,12 +18,21 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use url::Url;
 
-use crate::environment::{self, default_nix_subprocess_env};
+use crate::environment::{self, default_nix_subprocess_env, stale_flox_vars};
 pub use crate::models::environment_ref::{self, *};
+use crate::utils::clock::SharedClock;
 
 pub static FLOX_VERSION: Lazy<String> =
     Lazy::new(|| std::env::var("FLOX_VERSION").unwrap_or(env!("FLOX_VERSION").to_string()));
 
+/// The systems supported by flox
+pub const FLOX_SYSTEMS: [&str; 4] = [
+    "aarch64-darwin",
+    "aarch64-linux",
+    "x86_64-darwin",
+    "x86_64-linux",
+];
+
 /// The main API struct for our flox implementation
 ///
 /// A [Flox] instance serves as the context for nix invocations
@@ -30,7 +43,12 @@ pub static FLOX_VERSION: Lazy<String> =
 /// [Flox] will provide a preconfigured instance of the Nix API.
 /// By default this nix API uses the nix CLI.
 /// Preconfiguration includes environment variables and flox specific arguments.
-#[derive(Debug)]
+///
+/// [Flox] is cheap to clone, all fields are plain paths, strings and urls,
+/// apart from the [Flox::clock] which is shared between clones.
+/// Clones share the same [Flox::temp_dir], but they do not own it;
+/// the directory is managed (and cleaned up) by whoever created the instance.
+#[derive(Debug, Clone)]
 pub struct Flox {
     /// The directory pointing to the users flox configuration
     ///
@@ -44,7 +62,8 @@ pub struct Flox {
     ///
     /// Use [Vec] to preserve original ordering
     pub access_tokens: Vec<(String, String)>,
-    pub netrc_file: PathBuf,
+    /// netrc file injected in nix.conf, if it exists
+    pub netrc_file: Option<PathBuf>,
 
     pub system: String,
 
@@ -56,6 +75,28 @@ pub struct Flox {
     /// It's usually populated from the config during [Flox] initialization.
     /// Checking for [None] can be used to check if the use is logged in.
     pub floxhub_token: Option<FloxhubToken>,
+
+    /// A local git repository used as an object store
+    /// when cloning floxmeta repositories.
+    ///
+    /// Objects found in the reference are neither downloaded nor copied,
+    /// so the reference must remain available as long as the clones exist.
+    pub floxmeta_reference: Option<PathBuf>,
+
+    /// Timeout for individual git operations on floxmeta repositories
+    ///
+    /// [None] lets git operations run indefinitely.
+    pub git_timeout: Option<Duration>,
+
+    /// How many environments to fetch concurrently,
+    /// see [crate::models::floxmetav2::FloxmetaV2::fetch_environments]
+    ///
+    /// [None] uses the available parallelism of the system.
+    pub fetch_jobs: Option<NonZeroUsize>,
+
+    /// The source of the current time for time dependent behavior,
+    /// e.g. [crate::utils::clock::MockClock] in tests
+    pub clock: SharedClock,
 }
 
 pub trait FloxNixApi: NixBackend {
@@ -109,6 +150,43 @@ impl ResolvedInstallableMatch {
 }
 
 impl Flox {
+    /// The `FLOX_*` environment variables passed to processes spawned by flox,
+    /// e.g. git or nix
+    ///
+    /// Children see the same settings as this instance,
+    /// whatever the environment of the current process.
+    /// Secrets such as [Self::floxhub_token] are not included,
+    /// they are passed explicitly to the commands that need them.
+    pub fn child_env(&self) -> BTreeMap<String, String> {
+        let path = |path: &PathBuf| path.to_string_lossy().into_owned();
+        BTreeMap::from([
+            ("FLOX_SYSTEM", self.system.clone()),
+            ("FLOX_CONFIG_DIR", path(&self.config_dir)),
+            ("FLOX_CACHE_DIR", path(&self.cache_dir)),
+            ("FLOX_DATA_DIR", path(&self.data_dir)),
+            ("FLOX_FLOXHUB_URL", self.floxhub.base_url().to_string()),
+            ("FLOX_VERSION", FLOX_VERSION.to_string()),
+        ])
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect()
+    }
+
+    /// The environment of nix processes
+    /// spawned by a process that `inherited` the given environment variables
+    ///
+    /// Like git, nix sees the [Self::child_env] of this instance.
+    /// Nix backends can only set variables and not remove them,
+    /// so inherited `FLOX_*` variables are set to empty values instead.
+    fn nix_env(&self, inherited: impl IntoIterator<Item = OsString>) -> HashMap<String, String> {
+        let mut env = default_nix_subprocess_env();
+        env.extend(self.child_env());
+        for var in stale_flox_vars(inherited, |name| env.contains_key(name)) {
+            env.insert(var.to_string_lossy().into_owned(), String::new());
+        }
+        env
+    }
+
     /// Produce a new Nix Backend
     ///
     /// This method performs backend independent configuration of nix
@@ -140,7 +218,7 @@ impl Flox {
                 .into(),
                 extra_access_tokens: self.access_tokens.clone().into(),
                 flake_registry: None,
-                netrc_file: Some(self.netrc_file.clone().into()),
+                netrc_file: self.netrc_file.clone().map(Into::into),
                 connect_timeout: 5.into(),
                 ..Default::default()
             };
@@ -176,7 +254,7 @@ impl Flox {
                 std::fs::rename(temp_config_file_path, &global_config_file_path).unwrap()
             }
 
-            let mut env = default_nix_subprocess_env();
+            let mut env = self.nix_env(std::env::vars_os().map(|(var, _)| var));
             let _ = env.insert(
                 "NIX_USER_CONF_FILES".to_string(),
                 global_config_file_path.to_string_lossy().to_string(),
@@ -201,6 +279,42 @@ impl Flox {
 
         Nix::new(self, default_nix_args)
     }
+
+    /// Find the access token configured for `host`
+    ///
+    /// A token configured for exactly `host` is preferred.
+    /// Otherwise, a wildcard entry `*.<domain>` applies to all subdomains
+    /// of `<domain>` (but not to `<domain>` itself),
+    /// where the most specific wildcard wins.
+    /// A token for `github.com` is thus never used for `api.github.com`,
+    /// unless `*.github.com` is configured explicitly.
+    ///
+    /// Hosts are compared case-insensitively.
+    /// If a host is configured multiple times, the last entry wins,
+    /// consistent with how nix reads [Flox::access_tokens].
+    pub fn access_token_for(&self, host: &str) -> Option<&str> {
+        let host = host.to_ascii_lowercase();
+
+        let exact = self
+            .access_tokens
+            .iter()
+            .rev()
+            .find(|(configured, _)| configured.eq_ignore_ascii_case(&host));
+        if let Some((_, token)) = exact {
+            return Some(token);
+        }
+
+        self.access_tokens
+            .iter()
+            .filter_map(|(configured, token)| {
+                let domain = configured.strip_prefix("*.")?.to_ascii_lowercase();
+                let subdomain = host.strip_suffix(&domain)?.strip_suffix('.')?;
+                (!subdomain.is_empty()).then_some((domain.len(), token))
+            })
+            // for equally specific wildcards `max_by_key` picks the last one
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, token)| token.as_str())
+    }
 }
 
 pub static DEFAULT_FLOXHUB_URL: Lazy<Url> =
@@ -253,30 +367,62 @@ pub enum FloxhubTokenError {
 #[derive(Debug, Clone)]
 pub struct Floxhub {
     base_url: Url,
+    /// [None] if the api url can't be derived from the base url
+    /// and no override was provided
+    api_url: Option<Url>,
     git_url: Url,
     git_url_overridden: bool,
 }
 
 impl Floxhub {
+    /// Create a new floxhub instance from its base url
+    ///
+    /// The api and git urls are derived from the base url,
+    /// unless `git_url_override` is provided.
+    /// The git url is validated here, so that accessing it can not fail.
+    /// If the git url is overridden, the base url does not need to follow
+    /// the `hub` naming scheme, in which case [Self::api_url] is [None].
     pub fn new(base_url: Url, git_url_override: Option<Url>) -> Result<Self, FloxhubError> {
         let git_url_overridden = git_url_override.is_some();
-        let git_url = match git_url_override {
-            Some(git_url_override) => git_url_override,
-            None => Self::derive_git_url(&base_url)?,
+        let (api_url, git_url) = match git_url_override {
+            Some(git_url_override) => (Self::derive_api_url(&base_url).ok(), git_url_override),
+            None => (
+                Some(Self::derive_api_url(&base_url)?),
+                Self::derive_git_url(&base_url)?,
+            ),
         };
         Ok(Floxhub {
             base_url,
+            api_url,
             git_url,
             git_url_overridden,
         })
     }
 
+    /// Use a different url for the floxhub api
+    ///
+    /// This is useful for deployments that serve the api
+    /// from a host that can not be derived from the base url.
+    pub fn with_api_url_override(mut self, api_url: Url) -> Self {
+        self.api_url = Some(api_url);
+        self
+    }
+
     /// Return the base url of the floxhub instance
     /// might change to a more specific url in the future
     pub fn base_url(&self) -> &Url {
         &self.base_url
     }
 
+    /// Return the url of the floxhub api
+    ///
+    /// Endpoints of the api should be joined onto this url,
+    /// rather than onto [Self::base_url] or [Self::git_url].
+    /// [None] if the api url could not be derived, see [Self::new].
+    pub fn api_url(&self) -> Option<&Url> {
+        self.api_url.as_ref()
+    }
+
     pub fn git_url_override(&self) -> Option<&Url> {
         self.git_url_overridden.then_some(&self.git_url)
     }
@@ -290,18 +436,30 @@ impl Floxhub {
         &self.git_url
     }
 
-    fn derive_git_url(base_url: &Url) -> Result<Url, FloxhubError> {
-        let mut git_url = base_url.clone();
-        let host = git_url
+    /// Derive the api url from the base url
+    ///
+    /// `https://hub<rest>/` becomes `https://api<rest>/`
+    fn derive_api_url(base_url: &Url) -> Result<Url, FloxhubError> {
+        let mut api_url = base_url.clone();
+        let host = api_url
             .host_str()
             .ok_or(FloxhubError::NoHost(base_url.to_string()))?;
         let without_hub = host
             .strip_prefix("hub")
             .ok_or(FloxhubError::NoHubPrefix(base_url.to_string()))?;
         let with_api_prefix = format!("api{}", without_hub);
-        git_url
+        api_url
             .set_host(Some(&with_api_prefix))
             .map_err(|e| FloxhubError::InvalidFloxhubBaseUrl(with_api_prefix, e))?;
+        api_url.set_path("");
+        Ok(api_url)
+    }
+
+    /// Derive the git url from the base url
+    ///
+    /// The git interface is served by the api host under `/git`
+    fn derive_git_url(base_url: &Url) -> Result<Url, FloxhubError> {
+        let mut git_url = Self::derive_api_url(base_url)?;
         git_url.set_path("git");
         Ok(git_url)
     }
@@ -363,6 +521,10 @@ pub mod tests {
             uuid: Default::default(),
             floxhub: Floxhub::new(Url::from_str("https://hub.flox.dev").unwrap(), None).unwrap(),
             floxhub_token: None,
+            floxmeta_reference: None,
+            git_timeout: None,
+            fetch_jobs: None,
+            clock: crate::utils::clock::system_clock(),
         };
 
         init_global_manifest(&global_manifest_path(&flox)).unwrap();
@@ -370,6 +532,37 @@ pub mod tests {
         (flox, tempdir_handle)
     }
 
+    #[test]
+    fn test_child_env() {
+        let (mut flox, _tempdir) = flox_instance();
+        flox.floxhub_token = Some(FloxhubToken::new(FAKE_TOKEN.to_string()));
+
+        let env = flox.child_env();
+        assert_eq!(env["FLOX_SYSTEM"], flox.system);
+        assert_eq!(env["FLOX_CACHE_DIR"], flox.cache_dir.to_string_lossy());
+        assert_eq!(env["FLOX_DATA_DIR"], flox.data_dir.to_string_lossy());
+        assert_eq!(env["FLOX_CONFIG_DIR"], flox.config_dir.to_string_lossy());
+        assert_eq!(env["FLOX_FLOXHUB_URL"], "https://hub.flox.dev/");
+        assert!(env.keys().all(|key| key.starts_with("FLOX_")));
+        assert!(!env.contains_key("FLOX_FLOXHUB_TOKEN"));
+        assert!(env.values().all(|value| value != FAKE_TOKEN));
+    }
+
+    #[test]
+    fn test_nix_env() {
+        let (flox, _tempdir) = flox_instance();
+
+        let env = flox.nix_env([
+            OsString::from("FLOX_TEST_STALE_VAR"),
+            OsString::from("FLOX_SYSTEM"),
+            OsString::from("PATH"),
+        ]);
+        assert_eq!(env["FLOX_SYSTEM"], flox.system);
+        // overridden rather than inherited
+        assert_eq!(env["FLOX_TEST_STALE_VAR"], "");
+        assert!(!env.contains_key("PATH"));
+    }
+
     #[test]
     fn test_resolved_installable_match_to_installable() {
         let resolved = ResolvedInstallableMatch::new(
@@ -402,6 +595,81 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_derive_api_url() {
+        assert_eq!(
+            Floxhub::derive_api_url(&Url::from_str("https://hub.flox.dev").unwrap()).unwrap(),
+            Url::from_str("https://api.flox.dev").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_api_url_override() {
+        let floxhub = Floxhub::new(Url::from_str("https://hub.flox.dev").unwrap(), None)
+            .unwrap()
+            .with_api_url_override(Url::from_str("https://floxhub.example.com/api").unwrap());
+        assert_eq!(
+            floxhub.api_url(),
+            Some(&Url::from_str("https://floxhub.example.com/api").unwrap())
+        );
+        assert_eq!(
+            floxhub.git_url(),
+            &Url::from_str("https://api.flox.dev/git").unwrap()
+        );
+    }
+
+    /// A custom git url allows base urls not following the `hub` naming scheme
+    #[test]
+    fn test_git_url_override_without_hub_prefix() {
+        let floxhub = Floxhub::new(
+            Url::from_str("https://floxhub.example.com").unwrap(),
+            Some(Url::from_str("https://git.example.com").unwrap()),
+        )
+        .unwrap();
+        assert_eq!(floxhub.api_url(), None);
+        assert_eq!(
+            floxhub.git_url(),
+            &Url::from_str("https://git.example.com").unwrap()
+        );
+
+        assert!(matches!(
+            Floxhub::new(Url::from_str("https://floxhub.example.com").unwrap(), None),
+            Err(FloxhubError::NoHubPrefix(_))
+        ));
+    }
+
+    #[test]
+    fn test_access_token_for() {
+        let (mut flox, _tempdir_handle) = flox_instance();
+        flox.access_tokens = [
+            ("github.com", "github"),
+            ("*.example.com", "example-wildcard"),
+            ("*.internal.example.com", "internal-wildcard"),
+            ("exact.example.com", "exact"),
+            ("GitHub.com", "github-override"),
+        ]
+        .map(|(host, token)| (host.to_string(), token.to_string()))
+        .to_vec();
+
+        // exact matches win, the last entry overrides earlier ones
+        assert_eq!(flox.access_token_for("github.com"), Some("github-override"));
+        assert_eq!(flox.access_token_for("exact.example.com"), Some("exact"));
+        // plain entries do not apply to subdomains
+        assert_eq!(flox.access_token_for("api.github.com"), None);
+        // wildcards apply to subdomains, the most specific one wins
+        assert_eq!(
+            flox.access_token_for("api.example.com"),
+            Some("example-wildcard")
+        );
+        assert_eq!(
+            flox.access_token_for("git.internal.example.com"),
+            Some("internal-wildcard")
+        );
+        // wildcards do not apply to the domain itself or lookalike domains
+        assert_eq!(flox.access_token_for("example.com"), None);
+        assert_eq!(flox.access_token_for("badexample.com"), None);
+    }
+
     #[test]
     fn test_derive_git_url_dev() {
         assert_eq!(
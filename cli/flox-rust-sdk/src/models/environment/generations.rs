@@ -18,11 +18,14 @@
 //! ```
 
 use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
-use flox_types::version::Version;
+use flox_types::version::{Version, VersionError};
+use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 use thiserror::Error;
@@ -30,15 +33,18 @@ use thiserror::Error;
 use super::core_environment::CoreEnvironment;
 use super::{copy_dir_recursive, PathPointer, ENV_DIR_NAME};
 use crate::models::environment::MANIFEST_FILENAME;
+use crate::models::manifest::Manifest;
 use crate::providers::git::{
+    GitCommandBranchHashError,
     GitCommandError,
     GitCommandOptions,
     GitCommandProvider,
     GitProvider,
     GitRemoteCommandError,
+    SignatureStatus,
 };
 
-const GENERATIONS_METADATA_FILE: &str = "metadata.json";
+pub(crate) const GENERATIONS_METADATA_FILE: &str = "metadata.json";
 
 /// Generations as a branch in a (bare) git repository.
 /// In this state files are read only using `git show`.
@@ -80,6 +86,16 @@ pub struct Generations<State = ReadOnly> {
     /// Used to pick the correct branch when showing files, cloning, and pushing.
     branch: String,
 
+    /// Optional hook invoked after changes were committed to the generations branch
+    ///
+    /// See [Generations::set_commit_hook]
+    commit_hook: Option<CommitHook>,
+
+    /// Optional reporter for the steps of transactions on the generations branch
+    ///
+    /// See [Generations::set_progress_reporter]
+    progress_reporter: Option<ProgressReporter>,
+
     /// The state of the generations view
     ///
     /// Should remain private to enforce the invariant that [ReadWrite]
@@ -87,12 +103,102 @@ pub struct Generations<State = ReadOnly> {
     _state: State,
 }
 
+/// A commit made to a generations branch, passed to a [CommitHook]
+#[derive(Debug)]
+pub struct GenerationsCommit<'a> {
+    /// The name of the generations branch
+    pub branch: &'a str,
+    /// The revision of the new commit
+    pub rev: &'a str,
+    /// The paths changed by the commit, relative to the root of the branch
+    pub changed_paths: &'a [PathBuf],
+}
+
+/// The commit created by [Generations::add_generation]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitInfo {
+    /// The hash of the new commit
+    pub sha: String,
+    /// The commit message
+    pub message: String,
+}
+
+/// A callback observing commits made to a generations branch
+///
+/// Errors returned by the hook are logged but do not fail the commit.
+pub type CommitHook = Box<
+    dyn Fn(&GenerationsCommit) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+        + Send
+        + Sync,
+>;
+
+/// A potentially slow step of a transaction on a generations branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionStep {
+    /// Checking out the generations branch to make changes
+    Checkout,
+    /// Writing generation files and metadata
+    Write,
+    /// Staging the changes
+    Stage,
+    /// Committing the changes
+    Commit,
+    /// Pushing the commit to the generations branch of the original repository
+    Push,
+}
+
+/// Progress of a transaction, passed to a [ProgressReporter]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionEvent {
+    Started(TransactionStep),
+    /// The step finished, successfully or not, after the given time
+    Finished(TransactionStep, Duration),
+}
+
+/// A callback receiving [TransactionEvent]s, e.g. to show progress in a CLI
+pub type ProgressReporter = Box<dyn Fn(TransactionEvent) + Send + Sync>;
+
 impl<S> Generations<S> {
     /// Read the generations metadata for an environment
     pub fn metadata(&self) -> Result<AllGenerationsMetadata, GenerationsError> {
         read_metadata(&self.repo, &self.branch)
     }
 
+    /// Return the hash of the commit the generations branch currently points to
+    pub fn head_sha(&self) -> Result<String, GenerationsError> {
+        self.repo
+            .branch_hash(&self.branch)
+            .map_err(GenerationsError::ResolveBranch)
+    }
+
+    /// Read the generations metadata together with the hash of the commit it was read at
+    ///
+    /// The branch is resolved once and the metadata read from the resolved commit,
+    /// so the hash is guaranteed to match the returned metadata
+    /// even if the branch is updated concurrently.
+    /// Callers can compare the hash with a later [Self::head_sha]
+    /// to detect changes made in the meantime.
+    pub fn metadata_with_sha(&self) -> Result<(AllGenerationsMetadata, String), GenerationsError> {
+        let sha = self.head_sha()?;
+        let metadata = read_metadata(&self.repo, &sha)?;
+        Ok((metadata, sha))
+    }
+
+    /// Read the generations metadata and check the signature of the commit it was read at
+    ///
+    /// The read does not fail if the commit is unsigned or the signature is not trusted,
+    /// callers decide how to treat the returned [SignatureStatus].
+    pub fn metadata_with_signature(
+        &self,
+    ) -> Result<(AllGenerationsMetadata, SignatureStatus), GenerationsError> {
+        let (metadata, sha) = self.metadata_with_sha()?;
+        let status = self
+            .repo
+            .signature_status(&sha)
+            .map_err(GenerationsError::VerifySignature)?;
+        Ok((metadata, status))
+    }
+
     /// Read the manifest of a given generation and return its contents as a string
     pub fn manifest(&self, generation: usize) -> Result<String, GenerationsError> {
         let metadata = self.metadata()?;
@@ -107,7 +213,18 @@ impl<S> Generations<S> {
             ))
             .map_err(GenerationsError::ShowManifest)?;
 
-        return Ok(manifest_osstr.to_string_lossy().to_string());
+        manifest_osstr
+            .into_string()
+            .map_err(|_| GenerationsError::ManifestNotUtf8)
+    }
+
+    /// Read the manifest of a given generation and parse it
+    ///
+    /// Only the subset of the manifest exposed by [Manifest] is modeled,
+    /// the manifest is otherwise validated by pkgdb when it is locked.
+    pub fn parsed_manifest(&self, generation: usize) -> Result<Manifest, GenerationsError> {
+        let contents = self.manifest(generation)?;
+        toml::from_str(&contents).map_err(GenerationsError::DeserializeManifest)
     }
 
     /// Read the manifest of the current generation and return its contents as a string
@@ -120,6 +237,61 @@ impl<S> Generations<S> {
         self.manifest(*current_gen)
     }
 
+    /// Set a hook that is invoked after changes are committed
+    /// and pushed to the generations branch.
+    ///
+    /// The hook is kept when creating a writable copy using [Generations::writable].
+    /// By default no hook is set.
+    pub fn set_commit_hook(&mut self, hook: CommitHook) {
+        self.commit_hook = Some(hook);
+    }
+
+    /// Report the steps of transactions on the generations branch to `reporter`
+    ///
+    /// Like the commit hook, the reporter is kept by [Generations::writable].
+    /// By default no reporter is set.
+    pub fn set_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.progress_reporter = Some(reporter);
+    }
+
+    /// Run `step`, reporting its start and finish to the progress reporter, if any
+    fn step<T>(&self, step: TransactionStep, run: impl FnOnce() -> T) -> T {
+        let Some(reporter) = &self.progress_reporter else {
+            return run();
+        };
+
+        reporter(TransactionEvent::Started(step));
+        let start = Instant::now();
+        let result = run();
+        reporter(TransactionEvent::Finished(step, start.elapsed()));
+        result
+    }
+
+    /// Invoke the commit hook, if any, on a best effort basis
+    fn run_commit_hook(&self, changed_paths: &[PathBuf]) {
+        let Some(hook) = &self.commit_hook else {
+            return;
+        };
+
+        let rev = match self.repo.branch_hash(&self.branch) {
+            Ok(rev) => rev,
+            Err(e) => {
+                debug!("could not resolve revision for commit hook: {e}");
+                return;
+            },
+        };
+
+        let commit = GenerationsCommit {
+            branch: &self.branch,
+            rev: &rev,
+            changed_paths,
+        };
+
+        if let Err(e) = hook(&commit) {
+            debug!("commit hook failed: {e}");
+        }
+    }
+
     pub(super) fn git(&self) -> &GitCommandProvider {
         &self.repo
     }
@@ -131,6 +303,8 @@ impl Generations<ReadOnly> {
         Self {
             repo,
             branch,
+            commit_hook: None,
+            progress_reporter: None,
             _state: ReadOnly {},
         }
     }
@@ -180,21 +354,44 @@ impl Generations<ReadOnly> {
         self,
         tempdir: impl AsRef<Path>,
     ) -> Result<Generations<ReadWrite>, GenerationsError> {
-        let repo = checkout_to_tempdir(
-            &self.repo,
-            &self.branch,
-            tempfile::tempdir_in(tempdir).unwrap().into_path(),
-        )?;
+        let repo = self.step(TransactionStep::Checkout, || {
+            checkout_to_tempdir(
+                &self.repo,
+                &self.branch,
+                tempfile::tempdir_in(tempdir).unwrap().into_path(),
+            )
+        })?;
 
         Ok(Generations {
             repo,
             branch: self.branch,
+            commit_hook: self.commit_hook,
+            progress_reporter: self.progress_reporter,
             _state: ReadWrite {},
         })
     }
 }
 
 impl Generations<ReadWrite> {
+    /// Make sure the generations branch is checked out before starting a transaction
+    ///
+    /// A clean worktree on the generations branch is used as is,
+    /// which avoids refreshing the index of an unchanged worktree.
+    fn ensure_checked_out(&self) -> Result<(), GenerationsError> {
+        if self
+            .repo
+            .is_clean_and_on(&self.branch)
+            .map_err(GenerationsError::CheckoutBranch)?
+        {
+            return Ok(());
+        }
+
+        self.step(TransactionStep::Checkout, || {
+            self.repo.checkout(&self.branch, false)
+        })
+        .map_err(GenerationsError::CheckoutBranch)
+    }
+
     /// Return a mutable [CoreEnvironment] instance for a given generation
     /// contained in the generations branch.
     ///
@@ -242,13 +439,17 @@ impl Generations<ReadWrite> {
     /// If the generation already exists, it will be overwritten.
     ///
     /// If `set_current` is true, the generation will also be set as the current generation.
+    ///
+    /// Returns the commit that registered the generation.
     fn register_generation(
         &mut self,
         environment: &mut CoreEnvironment,
         generation: usize,
         description: String,
         set_current: bool,
-    ) -> Result<(), GenerationsError> {
+    ) -> Result<CommitInfo, GenerationsError> {
+        self.ensure_checked_out()?;
+
         let mut generation_metadata = SingleGenerationMetadata::new(description.clone());
 
         let mut metadata = self.metadata()?;
@@ -262,34 +463,38 @@ impl Generations<ReadWrite> {
             .generations
             .insert(generation.into(), generation_metadata);
 
-        write_metadata_file(metadata, self.repo.path())?;
-
         let generation_path = self.repo.path().join(generation.to_string());
-        let env_path = generation_path.join(ENV_DIR_NAME);
-        fs::create_dir_all(&env_path).unwrap();
+        self.step(TransactionStep::Write, || {
+            write_metadata_file(metadata, self.repo.path())?;
 
-        // copy `env/`, i.e. manifest and lockfile (if it exists) and possibly other assets
-        // copy into `<generation>/env/` to make creating `PathEnvironment` easier
-        copy_dir_recursive(&environment.path(), &env_path, true).unwrap();
+            let env_path = generation_path.join(ENV_DIR_NAME);
+            fs::create_dir_all(&env_path).unwrap();
 
-        self.repo
-            .add(&[&generation_path])
-            .map_err(GenerationsError::StageChanges)?;
-        self.repo
-            .add(&[Path::new(GENERATIONS_METADATA_FILE)])
-            .map_err(GenerationsError::StageChanges)?;
+            // copy `env/`, i.e. manifest and lockfile (if it exists) and possibly other assets
+            // copy into `<generation>/env/` to make creating `PathEnvironment` easier
+            copy_dir_recursive(&environment.path(), &env_path, true).unwrap();
+            Ok::<_, GenerationsError>(())
+        })?;
 
-        self.repo
-            .commit(&format!(
-                "Create generation {}\n\n{}",
-                generation, description
-            ))
+        self.step(TransactionStep::Stage, || {
+            self.repo.add(&[&generation_path])?;
+            self.repo.add(&[Path::new(GENERATIONS_METADATA_FILE)])
+        })
+        .map_err(GenerationsError::StageChanges)?;
+
+        let message = format!("Create generation {}\n\n{}", generation, description);
+        self.step(TransactionStep::Commit, || self.repo.commit(&message))
             .map_err(GenerationsError::CommitChanges)?;
-        self.repo
-            .push("origin", false)
+        let sha = self.head_sha()?;
+        self.step(TransactionStep::Push, || self.repo.push("origin", false))
             .map_err(GenerationsError::CompleteTransaction)?;
 
-        Ok(())
+        self.run_commit_hook(&[
+            PathBuf::from(generation.to_string()),
+            PathBuf::from(GENERATIONS_METADATA_FILE),
+        ]);
+
+        Ok(CommitInfo { sha, message })
     }
 
     /// Create a new generation from an existing environment
@@ -302,11 +507,14 @@ impl Generations<ReadWrite> {
     ///
     /// This method assigns a new sequential generation number
     /// and sets it as the current generation.
+    ///
+    /// Returns the commit that registered the generation,
+    /// e.g. to report it to the user.
     pub fn add_generation(
         &mut self,
         environment: &mut CoreEnvironment,
         description: String,
-    ) -> Result<(), GenerationsError> {
+    ) -> Result<CommitInfo, GenerationsError> {
         // keys should all be numbers (but)
         let max = self
             .metadata()?
@@ -328,6 +536,8 @@ impl Generations<ReadWrite> {
     /// it should first be realized using [Self::realize_generation].
     #[allow(unused)]
     fn set_current_generation(&mut self, generation: usize) -> Result<(), GenerationsError> {
+        self.ensure_checked_out()?;
+
         let mut metadata = self.metadata()?;
 
         let generation_metadata = metadata.generations.contains_key(&generation.into());
@@ -337,15 +547,23 @@ impl Generations<ReadWrite> {
 
         metadata.current_gen = Some(generation.into());
 
-        write_metadata_file(metadata, self.repo.path())?;
+        self.step(TransactionStep::Write, || {
+            write_metadata_file(metadata, self.repo.path())
+        })?;
 
-        self.repo
-            .add(&[Path::new(GENERATIONS_METADATA_FILE)])
-            .unwrap();
-        self.repo
-            .commit(&format!("Set current generation to {}", generation))
+        self.step(TransactionStep::Stage, || {
+            self.repo.add(&[Path::new(GENERATIONS_METADATA_FILE)])
+        })
+        .unwrap();
+        self.step(TransactionStep::Commit, || {
+            self.repo
+                .commit(&format!("Set current generation to {}", generation))
+        })
+        .unwrap();
+        self.step(TransactionStep::Push, || self.repo.push("origin", false))
             .unwrap();
-        self.repo.push("origin", false).unwrap();
+
+        self.run_commit_hook(&[PathBuf::from(GENERATIONS_METADATA_FILE)]);
 
         Ok(())
     }
@@ -373,6 +591,12 @@ pub enum GenerationsError {
     ShowMetadata(#[source] GitCommandError),
     #[error("could not parse generations metadata")]
     DeserializeMetadata(#[source] serde_json::Error),
+    #[error("generations metadata uses an unsupported version, it may have been written by a newer version of flox")]
+    UnsupportedVersion(#[source] VersionError),
+    #[error("generations metadata is empty, the environment may need to be recreated")]
+    EmptyMetadata,
+    #[error("generations metadata is not valid UTF-8, the environment may be corrupted")]
+    MetadataNotUtf8,
     // endregion
 
     // region: generation errors
@@ -383,6 +607,12 @@ pub enum GenerationsError {
     // endregion
 
     // region: repo/transaction
+    #[error("could not check out generations branch")]
+    CheckoutBranch(#[source] GitCommandError),
+    #[error("could not resolve generations branch")]
+    ResolveBranch(#[source] GitCommandBranchHashError),
+    #[error("could not verify signature of generations branch")]
+    VerifySignature(#[source] GitCommandError),
     #[error("could not clone generations branch")]
     CloneToFS(#[source] GitRemoteCommandError),
     #[error("could not stage changes")]
@@ -398,6 +628,10 @@ pub enum GenerationsError {
     WriteManifest(#[source] std::io::Error),
     #[error("could not show manifest file")]
     ShowManifest(#[source] GitCommandError),
+    #[error("manifest is not valid UTF-8")]
+    ManifestNotUtf8,
+    #[error("could not parse manifest")]
+    DeserializeManifest(#[source] toml::de::Error),
     // endregion
 }
 
@@ -420,25 +654,64 @@ fn read_metadata(
     repo: &GitCommandProvider,
     ref_name: &str,
 ) -> Result<AllGenerationsMetadata, GenerationsError> {
-    let metadata = {
-        let metadata_content = repo
-            .show(&format!("{}:{}", ref_name, GENERATIONS_METADATA_FILE))
-            .map_err(GenerationsError::ShowMetadata)?;
-        serde_json::from_str(&metadata_content.to_string_lossy())
-            .map_err(GenerationsError::DeserializeMetadata)?
-    };
-    Ok(metadata)
+    let metadata_content = repo
+        .show(&format!("{}:{}", ref_name, GENERATIONS_METADATA_FILE))
+        .map_err(GenerationsError::ShowMetadata)?;
+    parse_metadata(&decode_metadata(metadata_content)?)
+}
+
+/// Decode the raw content of a generations metadata file
+///
+/// JSON is always UTF-8, invalid bytes indicate a corrupted file
+/// and are reported rather than lossily replaced.
+fn decode_metadata(content: OsString) -> Result<String, GenerationsError> {
+    content
+        .into_string()
+        .map_err(|_| GenerationsError::MetadataNotUtf8)
+}
+
+/// Parses the content of a generations metadata file
+///
+/// The schema version is checked separately before parsing the full document,
+/// so that metadata written by a newer version of flox
+/// is reported as [GenerationsError::UnsupportedVersion]
+/// rather than as a generic parse error.
+/// Likewise, an empty file is reported as [GenerationsError::EmptyMetadata].
+pub(crate) fn parse_metadata(content: &str) -> Result<AllGenerationsMetadata, GenerationsError> {
+    #[derive(Deserialize)]
+    struct MetadataVersion {
+        #[serde(default)]
+        version: Option<u8>,
+    }
+
+    if content.trim().is_empty() {
+        return Err(GenerationsError::EmptyMetadata);
+    }
+
+    if let Ok(MetadataVersion {
+        version: Some(version),
+    }) = serde_json::from_str(content)
+    {
+        Version::<1>::check(version).map_err(GenerationsError::UnsupportedVersion)?;
+    }
+
+    serde_json::from_str(content).map_err(GenerationsError::DeserializeMetadata)
 }
 
 /// Serializes the generations metadata file to a path
 ///
 /// The path is expected to be a realized generations repository.
+///
+/// The file is written in a stable format to keep diffs between generations small:
+/// fields in declaration order, generations sorted by id,
+/// two space indentation and a trailing newline.
 fn write_metadata_file(
     metadata: AllGenerationsMetadata,
     realized_path: &Path,
 ) -> Result<(), GenerationsError> {
-    let metadata_content =
-        serde_json::to_string(&metadata).map_err(GenerationsError::SerializeMetadata)?;
+    let mut metadata_content =
+        serde_json::to_string_pretty(&metadata).map_err(GenerationsError::SerializeMetadata)?;
+    metadata_content.push('\n');
     let metadata_path = realized_path.join(GENERATIONS_METADATA_FILE);
     fs::write(metadata_path, metadata_content).map_err(GenerationsError::WriteMetadata)?;
     Ok(())
@@ -519,4 +792,339 @@ pub struct GenerationId(usize);
 mod tests {
     // todo: tests for this will be easier with the `init` method implemented
     // in https://github.com/flox/flox/pull/563
+
+    use std::sync::{Arc, Mutex};
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// Initialize a generations branch in a bare repository within `tempdir`
+    fn init_generations(tempdir: &Path) -> Generations {
+        let checkedout = tempdir.join("checkedout");
+        let bare = tempdir.join("bare");
+        fs::create_dir_all(&checkedout).unwrap();
+        fs::create_dir_all(&bare).unwrap();
+
+        let mut options = GitCommandOptions::default();
+        options.add_config_flag("user.name", "test");
+        options.add_config_flag("user.email", "test@example.invalid");
+
+        let pointer = PathPointer::new("test".parse().unwrap());
+        Generations::init(options, checkedout, bare, "test".to_string(), &pointer).unwrap()
+    }
+
+    /// Create an environment with the given manifest within `tempdir`
+    fn make_environment(tempdir: &Path, manifest: &str) -> CoreEnvironment {
+        let env_dir = tempdir.join("environment");
+        fs::create_dir_all(&env_dir).unwrap();
+        fs::write(env_dir.join(MANIFEST_FILENAME), manifest).unwrap();
+        CoreEnvironment::new(env_dir)
+    }
+
+    #[test]
+    fn commit_hook_observes_commits() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut generations = init_generations(tempdir.path());
+
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let observed_in_hook = observed.clone();
+        generations.set_commit_hook(Box::new(move |commit| {
+            observed_in_hook
+                .lock()
+                .unwrap()
+                .push((commit.rev.to_string(), commit.changed_paths.to_vec()));
+            Err("hook errors do not fail the commit".into())
+        }));
+
+        let mut generations = generations.writable(tempdir.path()).unwrap();
+        generations
+            .add_generation(
+                &mut make_environment(tempdir.path(), ""),
+                "test".to_string(),
+            )
+            .unwrap();
+
+        let observed = observed.lock().unwrap();
+        assert_eq!(observed.len(), 1);
+        assert_eq!(
+            observed[0].0,
+            generations.git().branch_hash("test").unwrap()
+        );
+        assert_eq!(observed[0].1, vec![
+            PathBuf::from("1"),
+            PathBuf::from(GENERATIONS_METADATA_FILE)
+        ]);
+    }
+
+    #[test]
+    fn progress_reporter_observes_steps() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut generations = init_generations(tempdir.path());
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_in_reporter = events.clone();
+        generations.set_progress_reporter(Box::new(move |event| {
+            events_in_reporter.lock().unwrap().push(event);
+        }));
+
+        let mut generations = generations.writable(tempdir.path()).unwrap();
+        generations
+            .add_generation(
+                &mut make_environment(tempdir.path(), ""),
+                "test".to_string(),
+            )
+            .unwrap();
+
+        let events = events.lock().unwrap();
+        let steps = events
+            .chunks(2)
+            .map(|pair| match pair {
+                [TransactionEvent::Started(started), TransactionEvent::Finished(finished, _)]
+                    if started == finished =>
+                {
+                    *started
+                },
+                _ => panic!("unexpected events: {pair:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(steps, vec![
+            TransactionStep::Checkout,
+            TransactionStep::Write,
+            TransactionStep::Stage,
+            TransactionStep::Commit,
+            TransactionStep::Push,
+        ]);
+    }
+
+    /// A transaction started off the generations branch checks it out again
+    #[test]
+    fn transaction_checks_out_generations_branch() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut generations = init_generations(tempdir.path())
+            .writable(tempdir.path())
+            .unwrap();
+        let branch = generations.branch.clone();
+        generations.git().checkout("other", true).unwrap();
+        assert!(!generations.git().is_clean_and_on(&branch).unwrap());
+
+        generations
+            .add_generation(
+                &mut make_environment(tempdir.path(), ""),
+                "test".to_string(),
+            )
+            .unwrap();
+        assert!(generations.git().is_clean_and_on(&branch).unwrap());
+        assert_eq!(generations.metadata().unwrap().generations.len(), 1);
+    }
+
+    #[test]
+    fn metadata_with_sha_tracks_branch() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut generations = init_generations(tempdir.path())
+            .writable(tempdir.path())
+            .unwrap();
+
+        let (metadata, initial_sha) = generations.metadata_with_sha().unwrap();
+        assert!(metadata.generations.is_empty());
+        assert_eq!(initial_sha, generations.head_sha().unwrap());
+
+        let commit = generations
+            .add_generation(
+                &mut make_environment(tempdir.path(), ""),
+                "test".to_string(),
+            )
+            .unwrap();
+        assert_eq!(commit.message, "Create generation 1\n\ntest");
+        assert_eq!(commit.sha.len(), 40);
+        assert!(commit.sha.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let (metadata, sha) = generations.metadata_with_sha().unwrap();
+        assert_eq!(metadata.generations.len(), 1);
+        assert_ne!(sha, initial_sha);
+        assert_eq!(sha, generations.head_sha().unwrap());
+        assert_eq!(sha, commit.sha);
+    }
+
+    /// HEAD and the state of the working tree (if any) of `repo`
+    fn repo_state(repo: &GitCommandProvider) -> (String, String) {
+        let output = |args: &[&str]| {
+            let output = repo.new_command().args(args).output().unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        let head = output(&["rev-parse", "--symbolic-full-name", "HEAD"])
+            + &output(&["rev-parse", "HEAD"]);
+        let status = if repo.workdir().is_some() {
+            output(&["status", "--porcelain", "--untracked-files=all"])
+        } else {
+            String::new()
+        };
+        (head, status)
+    }
+
+    /// Reading metadata must neither move HEAD nor touch the working tree,
+    /// as read-only generations are backed by a bare repository
+    #[test]
+    fn reading_metadata_does_not_check_out() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generations = init_generations(tempdir.path());
+
+        let before = repo_state(&generations.repo);
+        generations.metadata().unwrap();
+        generations.metadata_with_sha().unwrap();
+        generations.metadata_with_signature().unwrap();
+        assert_eq!(repo_state(&generations.repo), before);
+
+        let mut generations = generations.writable(tempdir.path()).unwrap();
+        generations
+            .add_generation(
+                &mut make_environment(tempdir.path(), ""),
+                "test".to_string(),
+            )
+            .unwrap();
+
+        let before = repo_state(&generations.repo);
+        generations.metadata().unwrap();
+        generations.metadata_with_sha().unwrap();
+        generations.metadata_with_signature().unwrap();
+        assert_eq!(repo_state(&generations.repo), before);
+    }
+
+    #[test]
+    fn metadata_with_signature_reports_unsigned() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let generations = init_generations(tempdir.path());
+
+        let (_, status) = generations.metadata_with_signature().unwrap();
+        assert_eq!(status, SignatureStatus::Unsigned);
+    }
+
+    #[test]
+    fn parsed_manifest() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut generations = init_generations(tempdir.path())
+            .writable(tempdir.path())
+            .unwrap();
+
+        let manifest = "[vars]\nfoo = \"bar\"\n";
+        generations
+            .add_generation(
+                &mut make_environment(tempdir.path(), manifest),
+                "test".to_string(),
+            )
+            .unwrap();
+
+        let parsed = generations.parsed_manifest(1).unwrap();
+        assert_eq!(parsed.vars.unwrap()["foo"].as_str(), Some("bar"));
+    }
+
+    #[test]
+    fn manifest_invalid_utf8() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut generations = init_generations(tempdir.path())
+            .writable(tempdir.path())
+            .unwrap();
+
+        let mut environment = make_environment(tempdir.path(), "");
+        fs::write(
+            environment.path().join(MANIFEST_FILENAME),
+            b"[vars]\nfoo = \"b\xe4r\"\n",
+        )
+        .unwrap();
+        generations
+            .add_generation(&mut environment, "test".to_string())
+            .unwrap();
+
+        assert!(matches!(
+            generations.manifest(1),
+            Err(GenerationsError::ManifestNotUtf8)
+        ));
+    }
+
+    #[test]
+    fn write_metadata_file_format() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let mut metadata = AllGenerationsMetadata {
+            current_gen: Some(2.into()),
+            ..Default::default()
+        };
+        for generation in [2_usize, 1] {
+            metadata
+                .generations
+                .insert(generation.into(), SingleGenerationMetadata {
+                    created: Utc.timestamp_opt(generation as i64, 0).unwrap(),
+                    last_active: None,
+                    description: format!("generation {generation}"),
+                });
+        }
+
+        write_metadata_file(metadata, tempdir.path()).unwrap();
+
+        let written = fs::read_to_string(tempdir.path().join(GENERATIONS_METADATA_FILE)).unwrap();
+        assert_eq!(written, indoc::indoc! {r#"
+            {
+              "currentGen": "2",
+              "generations": {
+                "1": {
+                  "created": 1,
+                  "lastActive": null,
+                  "description": "generation 1"
+                },
+                "2": {
+                  "created": 2,
+                  "lastActive": null,
+                  "description": "generation 2"
+                }
+              },
+              "version": 1
+            }
+            "#});
+    }
+
+    #[test]
+    fn parse_metadata_newer_version() {
+        let content = r#"{ "currentGen": null, "generations": {}, "version": 2 }"#;
+        let err = parse_metadata(content).expect_err("should not parse version 2");
+        assert!(
+            matches!(
+                err,
+                GenerationsError::UnsupportedVersion(VersionError {
+                    found: 2,
+                    supported: 1
+                })
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn decode_metadata_invalid_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let content = OsString::from_vec(b"{\"version\": 1, \"\xff\": {}}".to_vec());
+        assert!(matches!(
+            decode_metadata(content),
+            Err(GenerationsError::MetadataNotUtf8)
+        ));
+
+        let content = OsString::from("{\"version\": 1}");
+        assert_eq!(decode_metadata(content).unwrap(), "{\"version\": 1}");
+    }
+
+    #[test]
+    fn parse_metadata_empty() {
+        for content in ["", " \n\t"] {
+            let err = parse_metadata(content).expect_err("should not parse empty metadata");
+            assert!(matches!(err, GenerationsError::EmptyMetadata), "{err:?}");
+        }
+    }
+
+    #[test]
+    fn parse_metadata_implicit_version() {
+        let content = r#"{ "currentGen": null, "generations": {} }"#;
+        parse_metadata(content).expect("should parse implicit version 1");
+    }
 }
@@ -630,9 +630,9 @@ impl ManagedEnvironment {
                         .git
                         .fetch_ref("dynamicorigin", &format!("+{0}:{0}", remote_branch))
                         .map_err(|err| match err {
-                            GitRemoteCommandError::Command(e @ GitCommandError::Command(_)) => {
-                                ManagedEnvironmentError::Git(e)
-                            },
+                            GitRemoteCommandError::Command(
+                                e @ (GitCommandError::Command(_) | GitCommandError::GitNotFound(_)),
+                            ) => ManagedEnvironmentError::Git(e),
                             _ => ManagedEnvironmentError::Fetch(err),
                         })?;
                 }
@@ -961,7 +961,9 @@ impl ManagedEnvironment {
 
         let git_url = flox.floxhub.git_url();
 
-        let options = floxmeta_git_options(git_url, &pointer.owner, token);
+        let mut options = floxmeta_git_options(git_url, &pointer.owner, token);
+        options.set_timeout(flox.git_timeout);
+        options.set_flox_env(flox.child_env());
 
         let generations = Generations::init(
             options,
@@ -1004,9 +1006,7 @@ impl ManagedEnvironment {
 
         write_pointer_lockfile(
             dot_flox_path.join(GENERATION_LOCK_FILENAME),
-            &FloxmetaV2 {
-                git: temp_floxmeta_git,
-            },
+            &FloxmetaV2::from_git(flox, temp_floxmeta_git, pointer.owner.to_string()),
             remote_branch_name(&pointer),
             None,
         )?;
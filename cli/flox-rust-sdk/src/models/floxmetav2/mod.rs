@@ -1,27 +1,80 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use log::debug;
+use chrono::{DateTime, TimeZone, Utc};
+use fslock::LockFile;
+use futures::stream::{self, Stream};
+use globset::Glob;
+use log::{debug, warn};
 use thiserror::Error;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
+use super::environment::generations::{
+    parse_metadata,
+    Generations,
+    GenerationsError,
+    GENERATIONS_METADATA_FILE,
+};
 use super::environment::managed_environment::remote_branch_name;
 use super::environment::ManagedPointer;
-use super::environment_ref::EnvironmentOwner;
+use super::environment_ref::{EnvironmentOwner, EnvironmentRef};
 use crate::flox::{Flox, Floxhub, FloxhubError, FloxhubToken};
 use crate::providers::git::{
+    GitCommandBatchError,
     GitCommandBranchHashError,
+    GitCommandError,
     GitCommandOpenError,
     GitCommandOptions,
     GitCommandProvider,
     GitProvider,
     GitRemoteCommandError,
 };
+use crate::utils::clock::SharedClock;
+use crate::utils::errors::is_transient_remote_error;
+use crate::utils::resilience::{retry, CircuitBreaker, CircuitBreakerError, RetryPolicy};
 
 pub const FLOXMETA_DIR_NAME: &str = "meta";
 
-#[derive(Debug)]
+/// Directory in the cache dir recording the time of the last successful fetch
+/// of each floxmeta repository, see [FloxmetaV2::last_synced]
+const LAST_SYNC_DIR_NAME: &str = "floxmeta-last-sync";
+
+/// File in the git dir of a floxmeta repository
+/// locked while fetching all branches, see [FloxmetaV2::fetch_all_branches]
+///
+/// The lock is released by the OS if the fetching process dies,
+/// so a lock file left behind never blocks other processes.
+const FETCH_LOCK_FILE_NAME: &str = "flox-fetch.lock";
+
+/// How long [FloxmetaV2::fetch_all_branches] waits for a concurrent fetch
+/// before fetching without the lock
+const FETCH_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the fetch lock is tried while waiting for a concurrent fetch
+const FETCH_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Consecutive failed fetches after which [FloxmetaV2::poll_environment_changes]
+/// stops fetching for [POLL_FETCH_BACKOFF]
+const POLL_FETCH_FAILURES: u32 = 3;
+
+/// How long [FloxmetaV2::poll_environment_changes] stops fetching
+/// after [POLL_FETCH_FAILURES] consecutive failures
+const POLL_FETCH_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
 pub struct FloxmetaV2 {
     pub(super) git: GitCommandProvider,
+    /// The owner of the environments stored in this repository
+    pub(super) owner: String,
+    /// Used to record and check the time of syncs
+    pub(super) clock: SharedClock,
+    /// File recording the time of the last sync, see [last_sync_file]
+    pub(super) last_sync_file: PathBuf,
 }
 
 #[derive(Error, Debug)]
@@ -37,9 +90,145 @@ pub enum FloxmetaV2Error {
     FetchBranch(GitRemoteCommandError),
     #[error("Failed to clone environment: {0}")]
     CloneBranch(GitRemoteCommandError),
+    #[error("Failed to list environments on floxhub: {0}")]
+    ListRemoteBranches(GitRemoteCommandError),
 
     #[error(transparent)]
     FloxhubError(FloxhubError),
+
+    #[error("Could not list floxmeta repositories")]
+    ListFloxmeta(#[source] std::io::Error),
+    #[error("Failed to list branches: {0}")]
+    ListBranches(GitCommandError),
+    #[error("Failed to compact repository: {0}")]
+    Gc(GitCommandError),
+    #[error("Invalid environment name pattern")]
+    InvalidPattern(#[source] globset::Error),
+    #[error("Failed to read environment metadata: {0}")]
+    ReadMetadata(GitCommandBatchError),
+    #[error("Failed to compare environment with floxhub: {0}")]
+    Compare(GitCommandError),
+    #[error("environment {0} not found")]
+    EnvironmentNotFound(EnvironmentRef),
+    #[error("environment {env_ref} is not stored in the floxmeta repository of '{owner}'")]
+    OwnerMismatch {
+        env_ref: EnvironmentRef,
+        owner: String,
+    },
+    #[error("Failed to read environment: {0}")]
+    ReadEnvironment(GenerationsError),
+    #[error("Polling for environment changes failed")]
+    PollTask(#[source] tokio::task::JoinError),
+}
+
+/// How the local and floxhub copies of an environment branch relate,
+/// see [FloxmetaV2::remote_diff]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Number of local commits that are not on floxhub
+    pub ahead: usize,
+    /// Number of commits on floxhub that are not local
+    pub behind: usize,
+}
+
+/// What it takes to bring the local and floxhub copies of a branch in sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncAction {
+    /// Both copies point to the same commit
+    UpToDate,
+    /// The local branch can be fast-forwarded to floxhub
+    FastForward,
+    /// floxhub can be fast-forwarded by pushing the local branch
+    Push,
+    /// Both copies have new commits and need to be rebased or merged
+    Diverged,
+}
+
+impl SyncPlan {
+    /// Decide how to sync the branch based on the number of commits on either side
+    pub fn action(&self) -> SyncAction {
+        match (self.ahead, self.behind) {
+            (0, 0) => SyncAction::UpToDate,
+            (0, _) => SyncAction::FastForward,
+            (_, 0) => SyncAction::Push,
+            _ => SyncAction::Diverged,
+        }
+    }
+}
+
+/// An environment found by [FloxmetaV2::list_environments]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvironmentEntry {
+    /// The name of the environment, i.e. its branch in floxmeta
+    pub name: String,
+    /// The commit the environment branch points to
+    pub rev: String,
+    /// The description of the current generation,
+    /// [None] if there is no current generation or its metadata can't be read
+    pub description: Option<String>,
+    /// Why the metadata of the environment could not be read, if it could not
+    pub metadata_error: Option<String>,
+}
+
+/// A change observed by [FloxmetaV2::poll_environment_changes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvironmentChange {
+    Added(EnvironmentEntry),
+    /// The environment with the given name was removed
+    Removed(String),
+    /// The environment branch points to a different commit
+    Updated(EnvironmentEntry),
+}
+
+/// What [FloxmetaV2::poll_environment_changes] remembers between polls
+#[derive(Debug)]
+struct PollState {
+    /// The environments listed by the last successful poll
+    known: BTreeMap<String, EnvironmentEntry>,
+    /// The branches on floxhub at the last fetch, [None] before the first fetch
+    upstream: Option<BTreeSet<String>>,
+    /// Branches that were removed from floxhub but still exist locally
+    removed_upstream: BTreeSet<String>,
+    /// Stops fetching while floxhub keeps failing
+    breaker: CircuitBreaker,
+}
+
+/// The size of the object store before and after [FloxmetaV2::gc] in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcSummary {
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// The branches changed by [FloxmetaV2::fetch_all_branches]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FetchSummary {
+    /// Number of branches that did not exist locally before
+    pub created: usize,
+    /// Number of existing branches that were moved to a different commit
+    pub updated: usize,
+}
+
+/// The outcome of [FloxmetaV2::begin_fetch]
+enum FetchLock {
+    /// This process holds the lock and should fetch,
+    /// the lock is released when dropped.
+    /// [None] if the lock could not be taken, in which case we fetch anyway.
+    Acquired(Option<LockFile>),
+    /// A concurrent fetch completed successfully while waiting for the lock
+    Reused,
+}
+
+/// A local floxmeta repository that could not be opened by [FloxmetaV2::open_all]
+#[derive(Error, Debug)]
+#[error("Could not open floxmeta repository of '{owner}' at {path}")]
+pub struct FloxmetaOpenFailure {
+    /// The owner the repository belongs to, as derived from its directory name
+    pub owner: String,
+    /// The path of the repository
+    pub path: PathBuf,
+    #[source]
+    pub error: FloxmetaV2Error,
 }
 
 impl FloxmetaV2 {
@@ -66,19 +255,24 @@ impl FloxmetaV2 {
 
         let git_url = floxhub.git_url();
 
-        let git_options = floxmeta_git_options(git_url, &pointer.owner, token);
+        let mut git_options = floxmeta_git_options(git_url, &pointer.owner, token);
+        git_options.set_timeout(flox.git_timeout);
+        git_options.set_flox_env(flox.child_env());
         let branch = remote_branch_name(pointer);
 
-        let git = GitCommandProvider::clone_branch_with(
+        let git = GitCommandProvider::clone_branch_with_reference(
             git_options,
             format!("{}/{}/floxmeta", git_url, pointer.owner),
             path,
             branch,
             true,
+            flox.floxmeta_reference.as_deref(),
         )
         .map_err(FloxmetaV2Error::CloneBranch)?;
 
-        Ok(FloxmetaV2 { git })
+        let floxmeta = FloxmetaV2::from_git(flox, git, pointer.owner.to_string());
+        floxmeta.record_sync();
+        Ok(floxmeta)
     }
 
     /// Clone the floxmeta repository for the given user to the default path
@@ -120,7 +314,9 @@ impl FloxmetaV2 {
 
         let git_url = floxhub.git_url();
 
-        let git_options = floxmeta_git_options(git_url, &pointer.owner, token);
+        let mut git_options = floxmeta_git_options(git_url, &pointer.owner, token);
+        git_options.set_timeout(flox.git_timeout);
+        git_options.set_flox_env(flox.child_env());
 
         if !user_floxmeta_dir.as_ref().exists() {
             Err(FloxmetaV2Error::NotFound(pointer.owner.to_string()))?
@@ -128,16 +324,21 @@ impl FloxmetaV2 {
 
         let git = GitCommandProvider::open_with(git_options, user_floxmeta_dir)
             .map_err(FloxmetaV2Error::Open)?;
+        let floxmeta = FloxmetaV2::from_git(flox, git, pointer.owner.to_string());
+
         let branch: String = remote_branch_name(pointer);
-        if !git
+        if !floxmeta
+            .git
             .has_branch(&branch)
             .map_err(FloxmetaV2Error::CheckForBranch)?
         {
-            git.fetch_branch("dynamicorigin", &branch)
+            floxmeta
+                .fetch(&format!("refs/heads/{branch}:refs/heads/{branch}"))
                 .map_err(FloxmetaV2Error::FetchBranch)?;
+            floxmeta.record_sync();
         }
 
-        Ok(FloxmetaV2 { git })
+        Ok(floxmeta)
     }
 
     /// Open a floxmeta repository for a given user
@@ -148,6 +349,580 @@ impl FloxmetaV2 {
         Self::open_at(user_floxmeta_dir, flox, pointer)
     }
 
+    /// Open all floxmeta repositories found in XDG_DATA_HOME
+    ///
+    /// Repositories are opened with the floxhub and token of the flox instance.
+    /// Unlike [`FloxmetaV2::open`], no branch is fetched.
+    ///
+    /// A repository that fails to open does not abort the operation,
+    /// instead it is reported as a [FloxmetaOpenFailure]
+    /// so that callers can tell which owners succeeded and which failed.
+    pub fn open_all(
+        flox: &Flox,
+    ) -> Result<Vec<Result<(String, Self), FloxmetaOpenFailure>>, FloxmetaV2Error> {
+        let floxmeta_base_dir = flox.data_dir.join(FLOXMETA_DIR_NAME);
+        if !floxmeta_base_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = std::fs::read_dir(floxmeta_base_dir)
+            .map_err(FloxmetaV2Error::ListFloxmeta)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(FloxmetaV2Error::ListFloxmeta)?;
+        entries.sort();
+
+        let token = flox.floxhub_token.as_ref();
+        let git_url = flox.floxhub.git_url();
+
+        let floxmetas = entries
+            .into_iter()
+            .filter(|path| path.is_dir())
+            .map(|path| {
+                let owner = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let mut git_options = floxmeta_git_options(git_url, &owner, token);
+                git_options.set_timeout(flox.git_timeout);
+                git_options.set_flox_env(flox.child_env());
+
+                match GitCommandProvider::open_with(git_options, &path) {
+                    Ok(git) => Ok((owner.clone(), FloxmetaV2::from_git(flox, git, owner))),
+                    Err(e) => Err(FloxmetaOpenFailure {
+                        owner,
+                        path,
+                        error: FloxmetaV2Error::Open(e),
+                    }),
+                }
+            })
+            .collect();
+
+        Ok(floxmetas)
+    }
+
+    /// List the environments in this repository
+    /// whose name matches the glob pattern `name_pattern`, if given
+    ///
+    /// The metadata of all environments is read in a single batch,
+    /// so listing is fast even with many environments.
+    pub fn list_environments(
+        &self,
+        name_pattern: Option<&str>,
+    ) -> Result<Vec<EnvironmentEntry>, FloxmetaV2Error> {
+        let matcher = name_pattern
+            .map(|pattern| Glob::new(pattern).map(|glob| glob.compile_matcher()))
+            .transpose()
+            .map_err(FloxmetaV2Error::InvalidPattern)?;
+
+        let branches = self
+            .git
+            .branch_hashes()
+            .map_err(FloxmetaV2Error::ListBranches)?
+            .into_iter()
+            .filter(|(name, _)| matcher.as_ref().map_or(true, |m| m.is_match(name)))
+            .collect::<Vec<_>>();
+
+        let metadata_objects = branches
+            .iter()
+            .map(|(_, rev)| format!("{rev}:{GENERATIONS_METADATA_FILE}"))
+            .collect::<Vec<_>>();
+        let metadata = self
+            .git
+            .show_many(&metadata_objects)
+            .map_err(FloxmetaV2Error::ReadMetadata)?;
+
+        let environments = branches
+            .into_iter()
+            .zip(metadata)
+            .map(|((name, rev), metadata)| {
+                let metadata = metadata
+                    .ok_or_else(|| format!("{GENERATIONS_METADATA_FILE} is missing"))
+                    .and_then(|content| {
+                        String::from_utf8(content)
+                            .map_err(|_| format!("{GENERATIONS_METADATA_FILE} is not valid UTF-8"))
+                    })
+                    .and_then(|content| {
+                        parse_metadata(&content).map_err(|e| {
+                            format!("could not parse {GENERATIONS_METADATA_FILE}: {e}")
+                        })
+                    });
+
+                let (description, metadata_error) = match metadata {
+                    Ok(metadata) => {
+                        let description = metadata.current_gen.and_then(|current_gen| {
+                            metadata
+                                .generations
+                                .get(&current_gen)
+                                .map(|generation| generation.description.clone())
+                        });
+                        (description, None)
+                    },
+                    Err(e) => {
+                        warn!("could not read the metadata of environment '{name}': {e}");
+                        (None, Some(e))
+                    },
+                };
+
+                EnvironmentEntry {
+                    name,
+                    rev,
+                    description,
+                    metadata_error,
+                }
+            })
+            .collect();
+        Ok(environments)
+    }
+
+    /// Observe changes to the environments in this repository
+    ///
+    /// The environments are listed when this is called
+    /// and again every `interval` while the returned stream is polled,
+    /// emitting the environments that were added, removed or updated in between.
+    /// Before listing, all branches are fetched from floxhub,
+    /// unless the repository was synced less than `interval` ago.
+    /// Fetching leaves local branches in place,
+    /// so environments deleted on floxhub are detected by listing the remote branches
+    /// and reported as removed.
+    ///
+    /// Errors are emitted without ending the stream,
+    /// the next poll compares against the last successful listing.
+    /// Only if the polling task itself fails, e.g. because it panicked,
+    /// [FloxmetaV2Error::PollTask] is emitted and the stream ends.
+    pub fn poll_environment_changes(
+        &self,
+        interval: Duration,
+    ) -> Result<impl Stream<Item = Result<EnvironmentChange, FloxmetaV2Error>> + '_, FloxmetaV2Error>
+    {
+        let state = PollState {
+            known: self.environments_by_name()?,
+            upstream: None,
+            removed_upstream: BTreeSet::new(),
+            breaker: CircuitBreaker::new(
+                NonZeroU32::new(POLL_FETCH_FAILURES).unwrap(),
+                POLL_FETCH_BACKOFF,
+                self.clock.clone(),
+            ),
+        };
+
+        Ok(stream::unfold(
+            Some((state, VecDeque::new())),
+            move |seed| async move {
+                let (mut state, mut pending) = seed?;
+                loop {
+                    if let Some(change) = pending.pop_front() {
+                        return Some((Ok(change), Some((state, pending))));
+                    }
+
+                    tokio::time::sleep(interval).await;
+
+                    // git blocks, and may wait for a concurrent fetch
+                    let floxmeta = self.clone();
+                    let polled = tokio::task::spawn_blocking(move || {
+                        let changes = floxmeta.poll_once(interval, &mut state);
+                        (state, changes)
+                    })
+                    .await;
+                    let (returned_state, changes) = match polled {
+                        Ok(polled) => polled,
+                        Err(e) => return Some((Err(FloxmetaV2Error::PollTask(e)), None)),
+                    };
+                    state = returned_state;
+
+                    match changes {
+                        Ok(changes) => pending.extend(changes),
+                        Err(e) => return Some((Err(e), Some((state, pending)))),
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Fetch if necessary and list the changes since the last poll,
+    /// see [FloxmetaV2::poll_environment_changes]
+    ///
+    /// `state` is only updated if polling succeeds.
+    fn poll_once(
+        &self,
+        interval: Duration,
+        state: &mut PollState,
+    ) -> Result<Vec<EnvironmentChange>, FloxmetaV2Error> {
+        let synced_recently = self.last_synced().map_or(false, |synced| {
+            (self.clock.now() - synced)
+                .to_std()
+                .map_or(true, |age| age < interval)
+        });
+
+        let fetched = !synced_recently
+            && match state
+                .breaker
+                .call(&self.owner, || self.fetch_all_branches())
+            {
+                Ok(_) => true,
+                Err(CircuitBreakerError::Open(_)) => {
+                    debug!("fetching keeps failing, listing local environments only");
+                    false
+                },
+                Err(CircuitBreakerError::Operation(e)) => return Err(e),
+            };
+
+        let mut removed_upstream = state.removed_upstream.clone();
+        let mut upstream = None;
+        if fetched {
+            let branches = self
+                .git
+                .remote_branches("dynamicorigin")
+                .map_err(FloxmetaV2Error::ListRemoteBranches)?;
+            if let Some(previous) = &state.upstream {
+                removed_upstream.extend(previous.difference(&branches).cloned());
+            }
+            removed_upstream.retain(|name| !branches.contains(name));
+            upstream = Some(branches);
+        }
+
+        let mut current = self.environments_by_name()?;
+        current.retain(|name, _| !removed_upstream.contains(name));
+        let changes = environment_changes(&state.known, &current);
+
+        state.known = current;
+        state.removed_upstream = removed_upstream;
+        if upstream.is_some() {
+            state.upstream = upstream;
+        }
+        Ok(changes)
+    }
+
+    fn environments_by_name(&self) -> Result<BTreeMap<String, EnvironmentEntry>, FloxmetaV2Error> {
+        Ok(self
+            .list_environments(None)?
+            .into_iter()
+            .map(|entry| (entry.name.clone(), entry))
+            .collect())
+    }
+
+    /// List the environments in all floxmeta repositories found in XDG_DATA_HOME
+    /// together with their owner
+    ///
+    /// Only repositories of `owner` are considered, if given.
+    /// See [FloxmetaV2::list_environments] for the meaning of `name_pattern`.
+    /// Repositories that fail to open are skipped, see [FloxmetaV2::open_all].
+    pub fn list_all_environments(
+        flox: &Flox,
+        owner: Option<&str>,
+        name_pattern: Option<&str>,
+    ) -> Result<Vec<(String, EnvironmentEntry)>, FloxmetaV2Error> {
+        let mut environments = Vec::new();
+        for floxmeta in Self::open_all(flox)? {
+            let (floxmeta_owner, floxmeta) = match floxmeta {
+                Ok(floxmeta) => floxmeta,
+                Err(failure) => {
+                    debug!("skipping floxmeta: {failure}");
+                    continue;
+                },
+            };
+            if owner.is_some_and(|owner| owner != floxmeta_owner) {
+                continue;
+            }
+
+            environments.extend(
+                floxmeta
+                    .list_environments(name_pattern)?
+                    .into_iter()
+                    .map(|entry| (floxmeta_owner.clone(), entry)),
+            );
+        }
+        Ok(environments)
+    }
+
+    /// Allow cancelling the git operations of the returned floxmeta via `cancellation`,
+    /// e.g. to abort fetching all branches on demand
+    ///
+    /// Cancelled operations fail with [GitCommandError::Cancelled].
+    pub fn with_cancellation(&self, cancellation: CancellationToken) -> Self {
+        FloxmetaV2 {
+            git: self.git.with_cancellation(cancellation),
+            owner: self.owner.clone(),
+            clock: self.clock.clone(),
+            last_sync_file: self.last_sync_file.clone(),
+        }
+    }
+
+    /// Compact the repository to keep operations fast as history accumulates
+    ///
+    /// See [GitProvider::gc] for the meaning of `aggressive`.
+    pub fn gc(&self, aggressive: bool) -> Result<GcSummary, FloxmetaV2Error> {
+        let size_before = self.git.object_store_size().map_err(FloxmetaV2Error::Gc)?;
+        self.git.gc(aggressive).map_err(FloxmetaV2Error::Gc)?;
+        let size_after = self.git.object_store_size().map_err(FloxmetaV2Error::Gc)?;
+
+        debug!("compacted floxmeta from {size_before} to {size_after} bytes");
+        Ok(GcSummary {
+            size_before,
+            size_after,
+        })
+    }
+
+    /// Fetch all environment branches of this floxmeta repository from floxhub
+    ///
+    /// Unlike [`FloxmetaV2::open`], which only fetches the branch of a single environment,
+    /// this mirrors every branch of the remote repository,
+    /// e.g. to inspect environments offline.
+    /// Depending on the number of environments this may transfer a lot of data.
+    ///
+    /// Local branches are force updated to match the remote,
+    /// local branches that do not exist remotely are left untouched.
+    ///
+    /// If another process is already fetching this repository,
+    /// this waits for it to finish and reuses its result if it succeeded.
+    /// A concurrent fetch that does not finish within [FETCH_LOCK_TIMEOUT],
+    /// e.g. because it hangs, is not waited for any longer.
+    /// The returned summary always compares the branches before and after the call,
+    /// including changes made by a reused concurrent fetch.
+    pub fn fetch_all_branches(&self) -> Result<FetchSummary, FloxmetaV2Error> {
+        self.fetch_all_branches_waiting(FETCH_LOCK_TIMEOUT)
+    }
+
+    /// [Self::fetch_all_branches], waiting at most `lock_timeout` for a concurrent fetch
+    fn fetch_all_branches_waiting(
+        &self,
+        lock_timeout: Duration,
+    ) -> Result<FetchSummary, FloxmetaV2Error> {
+        let before = self
+            .git
+            .branch_hashes()
+            .map_err(FloxmetaV2Error::ListBranches)?;
+
+        // held until the end of the fetch
+        let _lock = match self.begin_fetch(lock_timeout) {
+            FetchLock::Acquired(lock) => {
+                self.fetch("+refs/heads/*:refs/heads/*")
+                    .map_err(FloxmetaV2Error::FetchBranch)?;
+                self.record_sync();
+                lock
+            },
+            FetchLock::Reused => None,
+        };
+
+        let after = self
+            .git
+            .branch_hashes()
+            .map_err(FloxmetaV2Error::ListBranches)?;
+
+        let mut summary = FetchSummary::default();
+        for (branch, hash) in after {
+            match before.get(&branch) {
+                None => summary.created += 1,
+                Some(old_hash) if *old_hash != hash => summary.updated += 1,
+                Some(_) => {},
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Update the local copies of the given environments from floxhub
+    ///
+    /// Environments are fetched in parallel, using at most `jobs` concurrent fetches,
+    /// or the available parallelism of the system if `jobs` is `None`.
+    /// Environments of the same owner share a floxmeta repository
+    /// and are fetched one after the other to avoid contention on the repository.
+    ///
+    /// Returns one result per pointer, in the order of `pointers`.
+    pub fn fetch_environments(
+        flox: &Flox,
+        pointers: &[ManagedPointer],
+    ) -> Vec<Result<(), FloxmetaV2Error>> {
+        let jobs = flox
+            .fetch_jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map(NonZeroUsize::get)
+            .unwrap_or(1);
+
+        // group pointers by owner, keeping track of their original position
+        let mut by_owner: BTreeMap<&EnvironmentOwner, Vec<(usize, &ManagedPointer)>> =
+            BTreeMap::new();
+        for (index, pointer) in pointers.iter().enumerate() {
+            by_owner
+                .entry(&pointer.owner)
+                .or_default()
+                .push((index, pointer));
+        }
+
+        let queue = Mutex::new(by_owner.into_values());
+        let results = Mutex::new(
+            std::iter::repeat_with(|| None)
+                .take(pointers.len())
+                .collect::<Vec<Option<Result<(), FloxmetaV2Error>>>>(),
+        );
+
+        std::thread::scope(|scope| {
+            for _ in 0..jobs.min(pointers.len()) {
+                scope.spawn(|| loop {
+                    let Some(group) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    for (index, pointer) in group {
+                        let result = Self::open(flox, pointer).and_then(|floxmeta| {
+                            let branch = remote_branch_name(pointer);
+                            floxmeta
+                                .fetch(&format!("+{branch}:{branch}"))
+                                .map_err(FloxmetaV2Error::FetchBranch)?;
+                            floxmeta.record_sync();
+                            Ok(())
+                        });
+                        results.lock().unwrap()[index] = Some(result);
+                    }
+                });
+            }
+        });
+
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|result| result.expect("every environment is fetched"))
+            .collect()
+    }
+
+    /// Open the environment `env_ref` stored in this repository for reading
+    ///
+    /// The environment is read from its floxhub branch as last fetched,
+    /// use [FloxmetaV2::open] to fetch it first.
+    /// Fails with [FloxmetaV2Error::OwnerMismatch] if `env_ref` belongs to another owner,
+    /// with [FloxmetaV2Error::EnvironmentNotFound] if there is no such branch,
+    /// and with [FloxmetaV2Error::ReadEnvironment] if its generations can't be read.
+    pub fn environment(&self, env_ref: &EnvironmentRef) -> Result<Generations, FloxmetaV2Error> {
+        if env_ref.owner().to_string() != self.owner {
+            return Err(FloxmetaV2Error::OwnerMismatch {
+                env_ref: env_ref.clone(),
+                owner: self.owner.clone(),
+            });
+        }
+
+        // the floxhub branch of an environment is named after the environment,
+        // see [remote_branch_name]
+        let branch = env_ref.name().to_string();
+        if !self
+            .git
+            .has_branch(&branch)
+            .map_err(FloxmetaV2Error::CheckForBranch)?
+        {
+            return Err(FloxmetaV2Error::EnvironmentNotFound(env_ref.clone()));
+        }
+
+        let generations = Generations::new(self.git.clone(), branch);
+        generations
+            .metadata()
+            .map_err(FloxmetaV2Error::ReadEnvironment)?;
+        Ok(generations)
+    }
+
+    /// Compare the local `branch` with the same branch on floxhub
+    ///
+    /// The upstream branch is fetched into `refs/remotes/dynamicorigin/<branch>`,
+    /// local branches are not modified.
+    pub fn remote_diff(&self, branch: &str) -> Result<SyncPlan, FloxmetaV2Error> {
+        let remote_ref = format!("refs/remotes/dynamicorigin/{branch}");
+        self.fetch(&format!("+{branch}:{remote_ref}"))
+            .map_err(FloxmetaV2Error::FetchBranch)?;
+
+        let (ahead, behind) = self
+            .git
+            .ahead_behind(&format!("refs/heads/{branch}"), &remote_ref)
+            .map_err(FloxmetaV2Error::Compare)?;
+        Ok(SyncPlan { ahead, behind })
+    }
+
+    /// The time this repository was last successfully cloned or fetched from floxhub
+    ///
+    /// The time is recorded in the git dir of the repository,
+    /// so it is removed along with the repository.
+    /// Returns [None] if no fetch was recorded or the record can not be read.
+    /// Times in the future, e.g. after the system clock was adjusted,
+    /// are reported as the current time.
+    pub fn last_synced(&self) -> Option<DateTime<Utc>> {
+        let content = std::fs::read_to_string(&self.last_sync_file).ok()?;
+        let timestamp = content.trim().parse::<i64>().ok()?;
+        let last_synced = Utc.timestamp_opt(timestamp, 0).single()?;
+        Some(last_synced.min(self.clock.now()))
+    }
+
+    /// Record the current time as the time of the last successful fetch
+    ///
+    /// Failing to do so is logged, but does not fail the fetch.
+    fn record_sync(&self) {
+        let path = &self.last_sync_file;
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, self.clock.now().timestamp().to_string()));
+        if let Err(e) = written {
+            debug!("could not record last sync time in {path:?}: {e}");
+        }
+    }
+
+    /// Fetch `refspec` from floxhub, retrying failures to reach floxhub
+    fn fetch(&self, refspec: &str) -> Result<(), GitRemoteCommandError> {
+        retry(&RetryPolicy::default(), is_transient_remote_error, || {
+            self.git.fetch_ref("dynamicorigin", refspec)
+        })
+    }
+
+    /// Take the fetch lock of this repository,
+    /// waiting up to `timeout` for a concurrent fetch to finish if necessary
+    ///
+    /// Returns [FetchLock::Reused] if a concurrent fetch completed successfully
+    /// while waiting, so its result can be reused instead of fetching again.
+    /// If the concurrent fetch is still running after `timeout`,
+    /// the caller fetches without holding the lock.
+    fn begin_fetch(&self, timeout: Duration) -> FetchLock {
+        let path = self.git_dir().join(FETCH_LOCK_FILE_NAME);
+        let lock = LockFile::open(&path).and_then(|mut lock| {
+            if lock.try_lock()? {
+                return Ok((Some(lock), None));
+            }
+            // recorded with the precision of the last sync time
+            let waiting_since = self.clock.now().timestamp();
+            debug!("waiting for concurrent fetch of {path:?}");
+            let deadline = Instant::now() + timeout;
+            while !lock.try_lock()? {
+                if Instant::now() >= deadline {
+                    return Ok((None, Some(waiting_since)));
+                }
+                std::thread::sleep(FETCH_LOCK_POLL_INTERVAL);
+            }
+            Ok((Some(lock), Some(waiting_since)))
+        });
+
+        match lock {
+            Ok((lock, Some(waiting_since)))
+                if self
+                    .last_synced()
+                    .map_or(false, |synced| synced.timestamp() >= waiting_since) =>
+            {
+                debug!("reusing the result of a concurrent fetch");
+                drop(lock);
+                FetchLock::Reused
+            },
+            Ok((None, _)) => {
+                debug!("concurrent fetch of {path:?} did not finish in time, fetching anyway");
+                FetchLock::Acquired(None)
+            },
+            Ok((lock, _)) => FetchLock::Acquired(lock),
+            Err(e) => {
+                debug!("could not lock {path:?}, fetching anyway: {e}");
+                FetchLock::Acquired(None)
+            },
+        }
+    }
+
+    fn git_dir(&self) -> PathBuf {
+        match self.git.workdir() {
+            Some(workdir) => workdir.join(".git"),
+            None => self.git.path().to_path_buf(),
+        }
+    }
+
     pub fn new_in(
         user_floxmeta_dir: impl AsRef<Path>,
         flox: &Flox,
@@ -163,25 +938,82 @@ impl FloxmetaV2 {
 
         let git_url = floxhub.git_url();
 
-        let git_options = floxmeta_git_options(git_url, &pointer.owner, token);
+        let mut git_options = floxmeta_git_options(git_url, &pointer.owner, token);
+        git_options.set_timeout(flox.git_timeout);
+        git_options.set_flox_env(flox.child_env());
 
         let git = GitCommandProvider::init_with(git_options, user_floxmeta_dir, false).unwrap();
         git.rename_branch(&remote_branch_name(pointer)).unwrap();
 
-        Ok(FloxmetaV2 { git })
+        Ok(FloxmetaV2::from_git(flox, git, pointer.owner.to_string()))
+    }
+
+    /// Wrap an opened floxmeta repository of `owner`
+    pub(super) fn from_git(flox: &Flox, git: GitCommandProvider, owner: String) -> Self {
+        let last_sync_file = last_sync_file(flox, &owner, git.path());
+        FloxmetaV2 {
+            git,
+            owner,
+            clock: flox.clock.clone(),
+            last_sync_file,
+        }
     }
 }
 
+/// File in the cache dir recording the last sync of the floxmeta repository of `owner` at `path`
+///
+/// The file is keyed by the location of the repository,
+/// so that e.g. temporary clones do not share the sync time of the repository in [floxmeta_dir].
+fn last_sync_file(flox: &Flox, owner: &str, path: &Path) -> PathBuf {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let key = blake3::hash(path.as_os_str().as_bytes());
+    flox.cache_dir
+        .join(LAST_SYNC_DIR_NAME)
+        .join(format!("{owner}-{key}"))
+}
+
+/// Compare two listings of environments by name
+fn environment_changes(
+    previous: &BTreeMap<String, EnvironmentEntry>,
+    current: &BTreeMap<String, EnvironmentEntry>,
+) -> Vec<EnvironmentChange> {
+    let removed = previous
+        .keys()
+        .filter(|name| !current.contains_key(*name))
+        .map(|name| EnvironmentChange::Removed(name.clone()));
+
+    let added_or_updated = current
+        .values()
+        .filter_map(|entry| match previous.get(&entry.name) {
+            None => Some(EnvironmentChange::Added(entry.clone())),
+            Some(previous) if previous.rev != entry.rev => {
+                Some(EnvironmentChange::Updated(entry.clone()))
+            },
+            Some(_) => None,
+        });
+
+    removed.chain(added_or_updated).collect()
+}
+
 /// Returns the git options for interacting with floxmeta repositories
 ///
 /// * Disable global and system config
 ///   to avoid user config interfering with flox operations
 /// * Set required user config (name and email)
+/// * Disable git hooks, so that hooks installed in a floxmeta repository
+///   can not interfere with (or block) the commits flox makes.
+///   Callers that want hooks to run can override `core.hooksPath`
+///   on the returned options.
 /// * Configure a dynamic origin for the floxhub repository
 ///   to allow cloning and fetching from different floxhub hosts per user.
 ///   The floxhub host is derived from the floxhub url in the environment pointer.
 /// * Set authentication with the floxhub token using an inline credential helper
 ///   if a token is provided.
+///
+/// Without a token, git operates anonymously:
+/// cloning and fetching public floxmeta repositories succeeds,
+/// while pushing and accessing private repositories
+/// fails with [GitRemoteCommandError::AccessDenied].
 pub fn floxmeta_git_options(
     floxhub_git_url: &Url,
     floxhub_owner: &str,
@@ -198,6 +1030,10 @@ pub fn floxmeta_git_options(
     options.add_env_var("GIT_CONFIG_GLOBAL", "/dev/null");
     options.add_env_var("GIT_CONFIG_SYSTEM", "/dev/null");
 
+    // floxmeta commits are made by flox, not by users,
+    // so hooks meant for human authored commits should not apply
+    options.add_config_flag("core.hooksPath", "/dev/null");
+
     // provides a "dynamic" remote "dynamicorigin".
     //
     // either the floxhub url from the environment pointer
@@ -221,13 +1057,17 @@ pub fn floxmeta_git_options(
 
     // Set authentication with the floxhub token using an inline credential helper.
     // The credential helper should help avoinding a leak of the token in the process list.
+    // The token itself is only passed through the environment, never as an argument.
+    //
+    // The helper is scoped to the floxhub git url, so the token is not offered to other hosts.
+    // It only answers `get` requests, `store` and `erase` are ignored.
     //
     // If no token is provided, we still set the credential helper and pass an empty string as password
     // to enforce authentication failures and avoid fallback to pinentry
     options.add_env_var("FLOX_FLOXHUB_TOKEN", token);
     options.add_config_flag(
         &format!("credential.{floxhub_git_url}.helper"),
-        r#"!f(){ echo "username=oauth"; echo "password=$FLOX_FLOXHUB_TOKEN"; }; f"#,
+        r#"!f(){ test "$1" = get || return 0; echo "username=oauth"; echo "password=$FLOX_FLOXHUB_TOKEN"; }; f"#,
     );
 
     options
@@ -243,11 +1083,13 @@ pub(super) fn floxmeta_dir(flox: &Flox, owner: &EnvironmentOwner) -> PathBuf {
 #[cfg(feature = "impure-unit-tests")]
 mod tests {
     use std::fs;
+    use std::sync::Arc;
 
     use super::*;
     use crate::flox::tests::flox_instance;
     use crate::flox::DEFAULT_FLOXHUB_URL;
     use crate::providers::git::GitProvider;
+    use crate::utils::clock::{Clock, MockClock};
 
     /// Create an upstream floxmeta repository with an environment under a given base path
     fn create_fake_floxmeta(
@@ -265,6 +1107,38 @@ mod tests {
         git
     }
 
+    /// Create a fake floxhub in `base_path` that hosts the environment `floxtest/test`
+    ///
+    /// Returns a pointer to the environment and the upstream floxmeta repository.
+    fn create_fake_floxhub(flox: &Flox, base_path: &Path) -> (ManagedPointer, GitCommandProvider) {
+        let source_path = base_path.join("source");
+
+        let floxhub = Floxhub::new(
+            DEFAULT_FLOXHUB_URL.clone(),
+            Some(Url::from_directory_path(&source_path).unwrap()),
+        )
+        .unwrap();
+
+        let pointer = ManagedPointer::new(
+            "floxtest".parse().unwrap(),
+            "test".parse().unwrap(),
+            &floxhub,
+        );
+
+        let upstream = create_fake_floxmeta(&source_path, flox, &pointer);
+        (pointer, upstream)
+    }
+
+    /// Add the environment `other` to the `upstream` floxmeta repository
+    ///
+    /// Leaves `other` checked out.
+    fn add_other_branch(upstream: &GitCommandProvider) {
+        upstream.checkout("other", true).unwrap();
+        fs::write(upstream.path().join("other.txt"), "other").unwrap();
+        upstream.add(&[Path::new("other.txt")]).unwrap();
+        upstream.commit("other").unwrap();
+    }
+
     /// Test whether a floxmeta repository can be successfully cloned
     /// from a given floxhub host (here a git file:// url pointing to a fake floxmeta repo)
     /// and opened from an existing clone.
@@ -273,26 +1147,487 @@ mod tests {
         let _ = env_logger::try_init();
 
         let (flox, tempdir) = flox_instance();
-        let source_path = tempdir.path().join("source");
+        let (pointer, _upstream) = create_fake_floxhub(&flox, tempdir.path());
 
-        let floxhub = Floxhub::new(
-            DEFAULT_FLOXHUB_URL.clone(),
-            Some(Url::from_directory_path(&source_path).unwrap()),
+        FloxmetaV2::clone_to(tempdir.path().join("dest"), &flox, &pointer)
+            .expect("Cloning a floxmeta repo should succeed");
+        FloxmetaV2::open_at(tempdir.path().join("dest"), &flox, &pointer)
+            .expect("Opening a floxmeta repo should succeed");
+    }
+
+    #[test]
+    fn list_environments_with_metadata() {
+        let (flox, tempdir) = flox_instance();
+        let floxhub = Floxhub::new(DEFAULT_FLOXHUB_URL.clone(), None).unwrap();
+        let pointer = ManagedPointer::new(
+            "floxtest".parse().unwrap(),
+            "test".parse().unwrap(),
+            &floxhub,
+        );
+
+        let floxmeta =
+            FloxmetaV2::new_in(tempdir.path().join("floxmeta"), &flox, &pointer).unwrap();
+        let git = &floxmeta.git;
+        let metadata_path = git.path().join(GENERATIONS_METADATA_FILE);
+
+        fs::write(
+            &metadata_path,
+            r#"{
+            "currentGen": "1",
+            "generations": { "1": { "created": 0, "lastActive": null, "description": "initial" } }
+        }"#,
         )
         .unwrap();
+        git.add(&[&metadata_path]).unwrap();
+        git.commit("test").unwrap();
+
+        // an environment with unreadable metadata is still listed
+        git.checkout("other", true).unwrap();
+        fs::write(&metadata_path, "").unwrap();
+        git.add(&[&metadata_path]).unwrap();
+        git.commit("other").unwrap();
 
+        let environments = floxmeta.list_environments(None).unwrap();
+        assert_eq!(environments, vec![
+            EnvironmentEntry {
+                name: "other".to_string(),
+                rev: git.branch_hash("other").unwrap(),
+                description: None,
+                metadata_error: environments[0].metadata_error.clone(),
+            },
+            EnvironmentEntry {
+                name: "test".to_string(),
+                rev: git.branch_hash("test").unwrap(),
+                description: Some("initial".to_string()),
+                metadata_error: None,
+            },
+        ]);
+        assert!(environments[0]
+            .metadata_error
+            .as_ref()
+            .is_some_and(|e| e.starts_with("could not parse")));
+
+        let environments = floxmeta.list_environments(Some("te*")).unwrap();
+        assert_eq!(environments.len(), 1);
+        assert_eq!(environments[0].name, "test");
+
+        assert!(matches!(
+            floxmeta.list_environments(Some("[")),
+            Err(FloxmetaV2Error::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn environment_by_ref() {
+        let (flox, tempdir) = flox_instance();
+        let floxhub = Floxhub::new(DEFAULT_FLOXHUB_URL.clone(), None).unwrap();
         let pointer = ManagedPointer::new(
             "floxtest".parse().unwrap(),
             "test".parse().unwrap(),
             &floxhub,
         );
 
-        create_fake_floxmeta(&source_path, &flox, &pointer);
+        let floxmeta =
+            FloxmetaV2::new_in(tempdir.path().join("floxmeta"), &flox, &pointer).unwrap();
+        let git = &floxmeta.git;
+        let metadata_path = git.path().join(GENERATIONS_METADATA_FILE);
 
-        FloxmetaV2::clone_to(tempdir.path().join("dest"), &flox, &pointer)
-            .expect("Cloning a floxmeta repo should succeed");
-        FloxmetaV2::open_at(tempdir.path().join("dest"), &flox, &pointer)
-            .expect("Opening a floxmeta repo should succeed");
+        fs::write(
+            &metadata_path,
+            r#"{
+            "currentGen": "1",
+            "generations": { "1": { "created": 0, "lastActive": null, "description": "initial" } }
+        }"#,
+        )
+        .unwrap();
+        git.add(&[&metadata_path]).unwrap();
+        git.commit("test").unwrap();
+
+        git.checkout("broken", true).unwrap();
+        fs::write(&metadata_path, "").unwrap();
+        git.add(&[&metadata_path]).unwrap();
+        git.commit("broken").unwrap();
+
+        let environment = floxmeta
+            .environment(&"floxtest/test".parse().unwrap())
+            .unwrap();
+        assert_eq!(environment.metadata().unwrap().generations.len(), 1);
+
+        assert!(matches!(
+            floxmeta.environment(&"floxtest/missing".parse().unwrap()),
+            Err(FloxmetaV2Error::EnvironmentNotFound(_))
+        ));
+        // environments of other owners are not looked up by name
+        assert!(matches!(
+            floxmeta.environment(&"other/test".parse().unwrap()),
+            Err(FloxmetaV2Error::OwnerMismatch { .. })
+        ));
+        assert!(matches!(
+            floxmeta.environment(&"floxtest/broken".parse().unwrap()),
+            Err(FloxmetaV2Error::ReadEnvironment(
+                GenerationsError::EmptyMetadata
+            ))
+        ));
+    }
+
+    /// Cloning and fetching record the time of the last sync
+    #[test]
+    fn last_synced_is_recorded() {
+        let (flox, tempdir) = flox_instance();
+        let (pointer, _upstream) = create_fake_floxhub(&flox, tempdir.path());
+
+        let unsynced = FloxmetaV2::new_in(tempdir.path().join("new"), &flox, &pointer).unwrap();
+        assert_eq!(unsynced.last_synced(), None);
+
+        let before = Utc::now().timestamp();
+        let floxmeta = FloxmetaV2::clone(&flox, &pointer).unwrap();
+        let last_synced = floxmeta.last_synced().expect("clone should record a sync");
+        assert!(last_synced.timestamp() >= before);
+
+        // the sync time is kept in the cache dir, separately for each repository
+        let record = floxmeta.last_sync_file.clone();
+        assert!(record.starts_with(&flox.cache_dir));
+        assert_eq!(unsynced.last_synced(), None);
+
+        // a future timestamp is clamped to the current time
+        fs::write(&record, (Utc::now().timestamp() + 3600).to_string()).unwrap();
+        assert!(floxmeta.last_synced().unwrap() <= Utc::now());
+
+        fs::write(&record, "garbage").unwrap();
+        assert_eq!(floxmeta.last_synced(), None);
+
+        floxmeta.fetch_all_branches().unwrap();
+        assert!(floxmeta.last_synced().is_some());
+    }
+
+    /// Opening all floxmeta repositories reports failing repositories
+    /// without affecting the others.
+    #[test]
+    fn open_all_reports_partial_failure() {
+        let (flox, _tempdir) = flox_instance();
+        let meta_dir = flox.data_dir.join(FLOXMETA_DIR_NAME);
+
+        fs::create_dir_all(meta_dir.join("broken")).unwrap();
+        fs::create_dir_all(meta_dir.join("working")).unwrap();
+        GitCommandProvider::init(meta_dir.join("working"), true).unwrap();
+
+        let floxmetas = FloxmetaV2::open_all(&flox).unwrap();
+        assert_eq!(floxmetas.len(), 2);
+
+        let failure = floxmetas[0]
+            .as_ref()
+            .expect_err("broken should fail to open");
+        assert_eq!(failure.owner, "broken");
+        assert_eq!(failure.path, meta_dir.join("broken"));
+
+        let (owner, _) = floxmetas[1].as_ref().expect("working should open");
+        assert_eq!(owner, "working");
+    }
+
+    /// Fetching all branches creates missing branches
+    /// and reports which branches changed.
+    #[test]
+    fn fetch_all_branches_summary() {
+        let (flox, tempdir) = flox_instance();
+        let (pointer, upstream) = create_fake_floxhub(&flox, tempdir.path());
+        add_other_branch(&upstream);
+
+        let floxmeta = FloxmetaV2::clone(&flox, &pointer).unwrap();
+        assert_eq!(floxmeta.fetch_all_branches().unwrap(), FetchSummary {
+            created: 1,
+            updated: 0
+        });
+
+        fs::write(upstream.path().join("other.txt"), "changed").unwrap();
+        upstream.add(&[Path::new("other.txt")]).unwrap();
+        upstream.commit("changed").unwrap();
+
+        assert_eq!(floxmeta.fetch_all_branches().unwrap(), FetchSummary {
+            created: 0,
+            updated: 1
+        });
+    }
+
+    /// Commit a file to `branch` of the bare floxmeta repository
+    fn commit_locally(floxmeta: &FloxmetaV2, worktree_path: &Path, branch: &str, file: &str) {
+        let worktree = floxmeta
+            .git
+            .temporary_worktree(worktree_path, branch)
+            .unwrap();
+        fs::write(worktree.git().path().join(file), file).unwrap();
+        worktree.git().add(&[Path::new(file)]).unwrap();
+        worktree.git().commit(file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn poll_environment_changes_emits_changes() {
+        use futures::StreamExt;
+
+        let (mut flox, tempdir) = flox_instance();
+        let clock = Arc::new(MockClock::new(Utc.timestamp_opt(1_700_000_000, 0).unwrap()));
+        flox.clock = clock.clone();
+        let (pointer, upstream) = create_fake_floxhub(&flox, tempdir.path());
+        let floxmeta = FloxmetaV2::clone(&flox, &pointer).unwrap();
+
+        let changes = floxmeta
+            .poll_environment_changes(Duration::from_millis(10))
+            .unwrap();
+        let mut changes = Box::pin(changes);
+
+        // branches added upstream are fetched once the last sync is old enough
+        add_other_branch(&upstream);
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(
+            changes.next().await.unwrap().unwrap(),
+            EnvironmentChange::Added(entry) if entry.name == "other"
+        ));
+
+        // local changes are observed without fetching
+        commit_locally(
+            &floxmeta,
+            &tempdir.path().join("worktree"),
+            "other",
+            "local",
+        );
+        assert!(matches!(
+            changes.next().await.unwrap().unwrap(),
+            EnvironmentChange::Updated(entry) if entry.name == "other"
+        ));
+
+        // branches removed upstream are reported, although fetching keeps them locally
+        upstream
+            .checkout(&remote_branch_name(&pointer), false)
+            .unwrap();
+        upstream.delete_branch("other", true).unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            changes.next().await.unwrap().unwrap(),
+            EnvironmentChange::Removed("other".to_string())
+        );
+        assert!(floxmeta.git.has_branch("other").unwrap());
+    }
+
+    #[test]
+    fn remote_diff_plans_sync() {
+        let (flox, tempdir) = flox_instance();
+        let (pointer, upstream) = create_fake_floxhub(&flox, tempdir.path());
+        let branch = remote_branch_name(&pointer);
+        let worktree_path = tempdir.path().join("worktree");
+        let floxmeta = FloxmetaV2::clone(&flox, &pointer).unwrap();
+
+        let commit_upstream = |file: &str| {
+            fs::write(upstream.path().join(file), file).unwrap();
+            upstream.add(&[Path::new(file)]).unwrap();
+            upstream.commit(file).unwrap();
+        };
+
+        let plan = floxmeta.remote_diff(&branch).unwrap();
+        assert_eq!(plan, SyncPlan {
+            ahead: 0,
+            behind: 0
+        });
+        assert_eq!(plan.action(), SyncAction::UpToDate);
+
+        // behind only
+        commit_upstream("upstream.txt");
+        let plan = floxmeta.remote_diff(&branch).unwrap();
+        assert_eq!(plan, SyncPlan {
+            ahead: 0,
+            behind: 1
+        });
+        assert_eq!(plan.action(), SyncAction::FastForward);
+
+        // remote_diff does not modify the local branch
+        assert_ne!(
+            floxmeta.git.branch_hash(&branch).unwrap(),
+            upstream.branch_hash(&branch).unwrap()
+        );
+        floxmeta
+            .git
+            .fetch_ref("dynamicorigin", &format!("+{branch}:{branch}"))
+            .unwrap();
+
+        // ahead only
+        commit_locally(&floxmeta, &worktree_path, &branch, "local.txt");
+        let plan = floxmeta.remote_diff(&branch).unwrap();
+        assert_eq!(plan, SyncPlan {
+            ahead: 1,
+            behind: 0
+        });
+        assert_eq!(plan.action(), SyncAction::Push);
+
+        // diverged
+        commit_upstream("more.txt");
+        let plan = floxmeta.remote_diff(&branch).unwrap();
+        assert_eq!(plan, SyncPlan {
+            ahead: 1,
+            behind: 1
+        });
+        assert_eq!(plan.action(), SyncAction::Diverged);
+    }
+
+    /// A fetch waits for a concurrent fetch and reuses its result
+    #[test]
+    fn fetch_all_branches_reuses_concurrent_fetch() {
+        let (flox, tempdir) = flox_instance();
+        let (pointer, upstream) = create_fake_floxhub(&flox, tempdir.path());
+        add_other_branch(&upstream);
+
+        let floxmeta = FloxmetaV2::clone(&flox, &pointer).unwrap();
+
+        // pretend another process is fetching and finishes shortly
+        let lock_path = floxmeta.git_dir().join(FETCH_LOCK_FILE_NAME);
+        let last_sync = floxmeta.last_sync_file.clone();
+        let git = floxmeta.git.clone();
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let concurrent = std::thread::spawn(move || {
+            let mut lock = LockFile::open(&lock_path).unwrap();
+            lock.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            git.fetch_ref("dynamicorigin", "+refs/heads/*:refs/heads/*")
+                .unwrap();
+            fs::write(last_sync, Utc::now().timestamp().to_string()).unwrap();
+        });
+        locked_rx.recv().unwrap();
+
+        // the summary includes the branch fetched by the concurrent process
+        assert_eq!(floxmeta.fetch_all_branches().unwrap(), FetchSummary {
+            created: 1,
+            updated: 0
+        });
+        concurrent.join().unwrap();
+        assert!(floxmeta.git.has_branch("other").unwrap());
+    }
+
+    /// A lock file left behind by a finished or crashed process does not block fetching
+    #[test]
+    fn fetch_all_branches_ignores_left_behind_lock_file() {
+        let (flox, tempdir) = flox_instance();
+        let (pointer, upstream) = create_fake_floxhub(&flox, tempdir.path());
+        add_other_branch(&upstream);
+
+        let floxmeta = FloxmetaV2::clone(&flox, &pointer).unwrap();
+
+        fs::write(floxmeta.git_dir().join(FETCH_LOCK_FILE_NAME), "").unwrap();
+
+        assert_eq!(floxmeta.fetch_all_branches().unwrap(), FetchSummary {
+            created: 1,
+            updated: 0
+        });
+    }
+
+    /// A concurrent fetch that never finishes does not block fetching indefinitely
+    #[test]
+    fn fetch_all_branches_stops_waiting_for_hung_fetch() {
+        let (flox, tempdir) = flox_instance();
+        let (pointer, upstream) = create_fake_floxhub(&flox, tempdir.path());
+        add_other_branch(&upstream);
+
+        let floxmeta = FloxmetaV2::clone(&flox, &pointer).unwrap();
+
+        // held for the rest of the test
+        let mut held = LockFile::open(&floxmeta.git_dir().join(FETCH_LOCK_FILE_NAME)).unwrap();
+        held.lock().unwrap();
+
+        let started = Instant::now();
+        assert_eq!(
+            floxmeta
+                .fetch_all_branches_waiting(Duration::from_millis(300))
+                .unwrap(),
+            FetchSummary {
+                created: 1,
+                updated: 0
+            }
+        );
+        assert!(started.elapsed() < FETCH_LOCK_TIMEOUT);
+        assert!(held.owns_lock());
+    }
+
+    /// Syncs are timed by the clock of [Flox]
+    #[test]
+    fn last_sync_follows_clock() {
+        let (mut flox, tempdir) = flox_instance();
+        let clock = Arc::new(MockClock::new(Utc.timestamp_opt(1_700_000_000, 0).unwrap()));
+        flox.clock = clock.clone();
+        let (pointer, upstream) = create_fake_floxhub(&flox, tempdir.path());
+        add_other_branch(&upstream);
+
+        let floxmeta = FloxmetaV2::clone(&flox, &pointer).unwrap();
+        assert_eq!(floxmeta.last_synced(), Some(clock.now()));
+
+        clock.advance(Duration::from_secs(60));
+
+        assert_eq!(floxmeta.fetch_all_branches().unwrap(), FetchSummary {
+            created: 1,
+            updated: 0
+        });
+        assert_eq!(floxmeta.last_synced(), Some(clock.now()));
+    }
+
+    /// Fetching multiple environments returns a result for each environment
+    /// in the order they were passed in.
+    #[test]
+    fn fetch_environments_in_order() {
+        let (mut flox, tempdir) = flox_instance();
+        flox.fetch_jobs = NonZeroUsize::new(2);
+
+        let (existing, _upstream) = create_fake_floxhub(&flox, tempdir.path());
+        let mut missing = existing.clone();
+        missing.name = "missing".parse().unwrap();
+        FloxmetaV2::clone(&flox, &existing).unwrap();
+
+        let results = FloxmetaV2::fetch_environments(&flox, &[missing, existing]);
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+    }
+
+    /// The floxhub token is passed to git through the environment only
+    /// and offered by a credential helper scoped to the floxhub git url.
+    #[test]
+    fn git_options_pass_token_via_env() {
+        let git_url = Url::parse("https://git.example.com/git").unwrap();
+        let token = FloxhubToken::new("flox_secret_token".to_string());
+        let command = floxmeta_git_options(&git_url, "owner", Some(&token)).new_command();
+
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert!(
+            args.iter().all(|arg| !arg.contains(token.secret())),
+            "token leaked into arguments: {args:?}"
+        );
+        assert!(args
+            .iter()
+            .any(|arg| arg.starts_with(&format!("credential.{git_url}.helper="))));
+
+        let token_env = command
+            .get_envs()
+            .find(|(key, _)| *key == "FLOX_FLOXHUB_TOKEN")
+            .and_then(|(_, value)| value);
+        assert_eq!(token_env, Some(std::ffi::OsStr::new(token.secret())));
+    }
+
+    /// Hooks are disabled by default, but can be re-enabled by the caller
+    #[test]
+    fn git_options_disable_hooks() {
+        let git_url = Url::parse("https://git.example.com/git").unwrap();
+        let args = |options: &GitCommandOptions| {
+            options
+                .new_command()
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let mut options = floxmeta_git_options(&git_url, "owner", None);
+        assert!(args(&options).contains(&"core.hooksPath=/dev/null".to_string()));
+
+        options.add_config_flag("core.hooksPath", ".git/hooks");
+        let args = args(&options);
+        assert!(args.contains(&"core.hooksPath=.git/hooks".to_string()));
+        assert!(!args.contains(&"core.hooksPath=/dev/null".to_string()));
     }
 
     ///// Test whether a floxmeta repository can be successfully cloned from floxhub
@@ -0,0 +1,90 @@
+//! A replaceable source of the current time
+//!
+//! Time dependent behavior, such as recording when floxmeta was last synced
+//! or deciding whether the result of a concurrent fetch can be reused,
+//! reads the time from a [Clock]
+//! rather than calling [Utc::now] directly.
+//! Tests can then use a [MockClock] and advance it explicitly
+//! instead of sleeping or writing timestamps in the past.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// A source of the current time
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// A [Clock] shared between a [crate::flox::Flox] instance
+/// and the objects created from it
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The [Clock] used outside of tests, reading the system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Return a new [SharedClock] reading the system time
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A [Clock] that only moves when told to
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl MockClock {
+    /// Create a clock that is stopped at `now`
+    pub fn new(now: DateTime<Utc>) -> Self {
+        MockClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    /// Set the time returned by [Clock::now]
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += chrono::Duration::from_std(duration).expect("duration is out of range");
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn mock_clock_advances() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(90));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
@@ -3,6 +3,14 @@ use std::path::PathBuf;
 
 use thiserror::Error;
 
+use crate::flox::FloxhubError;
+use crate::models::environment::generations::GenerationsError;
+use crate::models::environment::managed_environment::ManagedEnvironmentError;
+use crate::models::environment::remote_environment::RemoteEnvironmentError;
+use crate::models::environment::EnvironmentError2;
+use crate::models::floxmetav2::FloxmetaV2Error;
+use crate::providers::git::{GitCommandError, GitRemoteCommandError};
+
 #[derive(Error, Debug)]
 pub enum IoError {
     #[error("Couldn't create temp dir in {dir}: {err}")]
@@ -18,3 +26,240 @@ pub enum IoError {
     #[error("Couldn't create directory {dir}: {err}")]
     CreateDir { dir: PathBuf, err: io::Error },
 }
+
+/// Any error returned by the major subsystems of the SDK
+///
+/// Embedders that don't need to distinguish individual failures
+/// can convert subsystem errors into a [FloxError] with `?`
+/// and use the category helpers ([FloxError::is_network], [FloxError::is_auth],
+/// [FloxError::is_not_found]) to decide how to react.
+/// The original error is preserved in each variant.
+#[derive(Error, Debug)]
+pub enum FloxError {
+    #[error(transparent)]
+    Environment(#[from] EnvironmentError2),
+    #[error(transparent)]
+    ManagedEnvironment(#[from] ManagedEnvironmentError),
+    #[error(transparent)]
+    RemoteEnvironment(#[from] RemoteEnvironmentError),
+    #[error(transparent)]
+    Generations(#[from] GenerationsError),
+    #[error(transparent)]
+    Floxmeta(#[from] FloxmetaV2Error),
+    #[error(transparent)]
+    Floxhub(#[from] FloxhubError),
+    #[error(transparent)]
+    Git(#[from] GitCommandError),
+    #[error(transparent)]
+    GitRemote(#[from] GitRemoteCommandError),
+    #[error(transparent)]
+    Io(#[from] IoError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorCategory {
+    Network,
+    Auth,
+    NotFound,
+    Other,
+}
+
+impl FloxError {
+    /// The error was caused by a failure to reach a remote host,
+    /// including remote git operations that timed out
+    pub fn is_network(&self) -> bool {
+        self.category() == ErrorCategory::Network
+    }
+
+    /// Access to a remote resource was denied
+    pub fn is_auth(&self) -> bool {
+        self.category() == ErrorCategory::Auth
+    }
+
+    /// An environment, generation or remote ref does not exist
+    pub fn is_not_found(&self) -> bool {
+        self.category() == ErrorCategory::NotFound
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match self {
+            FloxError::Environment(e) => environment_category(e),
+            FloxError::ManagedEnvironment(e) => managed_environment_category(e),
+            FloxError::RemoteEnvironment(e) => remote_environment_category(e),
+            FloxError::Generations(e) => generations_category(e),
+            FloxError::Floxmeta(e) => floxmeta_category(e),
+            FloxError::Floxhub(_) => ErrorCategory::Other,
+            FloxError::Git(e) => git_category(e),
+            FloxError::GitRemote(e) => git_remote_category(e),
+            FloxError::Io(_) => ErrorCategory::Other,
+        }
+    }
+}
+
+/// Messages git prints if it failed to reach a remote
+const GIT_NETWORK_ERRORS: &[&str] = &[
+    "Could not resolve host",
+    "Could not read from remote repository",
+    "Connection refused",
+    "Connection timed out",
+    "Network is unreachable",
+    "unable to access",
+];
+
+/// Git subcommands that talk to a remote,
+/// only timeouts of these are attributed to the network
+const GIT_REMOTE_OPERATIONS: &[&str] = &["fetch", "push", "ls-remote", "clone"];
+
+fn git_category(e: &GitCommandError) -> ErrorCategory {
+    match e {
+        GitCommandError::BadExit(_, _, stderr)
+            if GIT_NETWORK_ERRORS.iter().any(|msg| stderr.contains(msg)) =>
+        {
+            ErrorCategory::Network
+        },
+        GitCommandError::TimedOut { operation, .. }
+            if GIT_REMOTE_OPERATIONS.contains(&operation.as_str()) =>
+        {
+            ErrorCategory::Network
+        },
+        _ => ErrorCategory::Other,
+    }
+}
+
+/// Whether `e` was caused by a failure to reach the remote,
+/// so that retrying the operation may succeed
+pub(crate) fn is_transient_remote_error(e: &GitRemoteCommandError) -> bool {
+    git_remote_category(e) == ErrorCategory::Network
+}
+
+fn git_remote_category(e: &GitRemoteCommandError) -> ErrorCategory {
+    match e {
+        GitRemoteCommandError::Command(e) => git_category(e),
+        GitRemoteCommandError::AccessDenied => ErrorCategory::Auth,
+        GitRemoteCommandError::RefNotFound(_) => ErrorCategory::NotFound,
+        GitRemoteCommandError::Diverged | GitRemoteCommandError::InvalidReference(_) => {
+            ErrorCategory::Other
+        },
+    }
+}
+
+fn floxmeta_category(e: &FloxmetaV2Error) -> ErrorCategory {
+    match e {
+        FloxmetaV2Error::NotFound(_) | FloxmetaV2Error::EnvironmentNotFound(_) => {
+            ErrorCategory::NotFound
+        },
+        FloxmetaV2Error::ReadEnvironment(e) => generations_category(e),
+        FloxmetaV2Error::OwnerMismatch { .. } => ErrorCategory::Other,
+        FloxmetaV2Error::FetchBranch(e)
+        | FloxmetaV2Error::CloneBranch(e)
+        | FloxmetaV2Error::ListRemoteBranches(e) => git_remote_category(e),
+        _ => ErrorCategory::Other,
+    }
+}
+
+fn generations_category(e: &GenerationsError) -> ErrorCategory {
+    match e {
+        GenerationsError::GenerationNotFound(_) | GenerationsError::NoGenerations => {
+            ErrorCategory::NotFound
+        },
+        GenerationsError::MakeBareClone(e)
+        | GenerationsError::CloneToFS(e)
+        | GenerationsError::CompleteTransaction(e) => git_remote_category(e),
+        _ => ErrorCategory::Other,
+    }
+}
+
+fn managed_environment_category(e: &ManagedEnvironmentError) -> ErrorCategory {
+    match e {
+        ManagedEnvironmentError::AccessDenied => ErrorCategory::Auth,
+        ManagedEnvironmentError::UpstreamNotFound(..) => ErrorCategory::NotFound,
+        ManagedEnvironmentError::OpenFloxmeta(e) => floxmeta_category(e),
+        ManagedEnvironmentError::Fetch(e)
+        | ManagedEnvironmentError::Push(e)
+        | ManagedEnvironmentError::FetchUpdates(e)
+        | ManagedEnvironmentError::ApplyUpdates(e) => git_remote_category(e),
+        ManagedEnvironmentError::InitializeFloxmeta(e)
+        | ManagedEnvironmentError::CreateFloxmetaDir(e)
+        | ManagedEnvironmentError::CreateGenerationFiles(e)
+        | ManagedEnvironmentError::CommitGeneration(e)
+        | ManagedEnvironmentError::ReadManifest(e) => generations_category(e),
+        _ => ErrorCategory::Other,
+    }
+}
+
+fn remote_environment_category(e: &RemoteEnvironmentError) -> ErrorCategory {
+    match e {
+        RemoteEnvironmentError::OpenManagedEnvironment(e)
+        | RemoteEnvironmentError::ResetManagedEnvironment(e)
+        | RemoteEnvironmentError::UpdateUpstream(e) => managed_environment_category(e),
+        RemoteEnvironmentError::GetLatestVersion(e) => floxmeta_category(e),
+        _ => ErrorCategory::Other,
+    }
+}
+
+fn environment_category(e: &EnvironmentError2) -> ErrorCategory {
+    match e {
+        EnvironmentError2::DotFloxNotFound(_) | EnvironmentError2::ManifestNotFound => {
+            ErrorCategory::NotFound
+        },
+        EnvironmentError2::ManagedEnvironment(e) => managed_environment_category(e),
+        EnvironmentError2::RemoteEnvironment(e) => remote_environment_category(e),
+        _ => ErrorCategory::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_nested_errors() {
+        let access_denied: FloxError = ManagedEnvironmentError::OpenFloxmeta(
+            FloxmetaV2Error::CloneBranch(GitRemoteCommandError::AccessDenied),
+        )
+        .into();
+        assert!(access_denied.is_auth());
+        assert!(!access_denied.is_network());
+        assert!(!access_denied.is_not_found());
+
+        let unreachable: FloxError = GitRemoteCommandError::Command(GitCommandError::BadExit(
+            128,
+            String::new(),
+            "fatal: unable to access 'https://git.hub.flox.dev/': Could not resolve host"
+                .to_string(),
+        ))
+        .into();
+        assert!(unreachable.is_network());
+
+        let timed_out: FloxError = GitCommandError::TimedOut {
+            operation: "fetch".to_string(),
+            timeout: std::time::Duration::from_secs(1),
+        }
+        .into();
+        assert!(timed_out.is_network());
+
+        let local_timed_out: FloxError = GitCommandError::TimedOut {
+            operation: "gc".to_string(),
+            timeout: std::time::Duration::from_secs(1),
+        }
+        .into();
+        assert!(!local_timed_out.is_network());
+
+        let not_found: FloxError =
+            EnvironmentError2::ManagedEnvironment(ManagedEnvironmentError::Fetch(
+                GitRemoteCommandError::RefNotFound("owner.name".to_string()),
+            ))
+            .into();
+        assert!(not_found.is_not_found());
+
+        let no_generation: FloxError = GenerationsError::GenerationNotFound(3).into();
+        assert!(no_generation.is_not_found());
+
+        let other: FloxError = GenerationsError::EmptyMetadata.into();
+        assert!(!other.is_network() && !other.is_auth() && !other.is_not_found());
+
+        let local_git: FloxError =
+            GitCommandError::BadExit(1, String::new(), "fatal: bad revision".to_string()).into();
+        assert!(!local_git.is_network());
+    }
+}
@@ -1,5 +1,7 @@
+pub mod clock;
 pub mod errors;
 pub mod guard;
+pub mod resilience;
 use std::path::Path;
 use std::time::SystemTime;
 use std::{fs, io};
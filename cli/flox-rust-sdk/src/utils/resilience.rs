@@ -0,0 +1,392 @@
+//! Retrying and circuit breaking for operations talking to remote hosts
+//!
+//! Such operations, e.g. fetching floxmeta from floxhub, may fail transiently.
+//! [retry] repeats an operation according to a [RetryPolicy],
+//! while a [CircuitBreaker] stops trying a host altogether
+//! after too many consecutive failures.
+//!
+//! The two compose by retrying within a call guarded by the breaker:
+//!
+//! ```ignore
+//! breaker.call(host, || retry(&policy, is_transient, || fetch(host)))
+//! ```
+//!
+//! Floxmeta fetches are retried, see [crate::models::floxmetav2],
+//! and [crate::models::floxmetav2::FloxmetaV2::poll_environment_changes]
+//! stops fetching for a while if floxhub keeps failing.
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use thiserror::Error;
+
+use super::clock::SharedClock;
+
+/// How often and how fast to retry a failing operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one
+    pub max_attempts: NonZeroU32,
+    /// The delay before the first retry, doubled with each subsequent retry
+    pub initial_delay: Duration,
+    /// The upper bound for the delay between two attempts
+    pub max_delay: Duration,
+    /// Randomize delays to avoid many clients retrying in lockstep
+    ///
+    /// If enabled, each delay is picked uniformly from `[delay / 2, delay]`.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: NonZeroU32::new(3).unwrap(),
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that runs the operation exactly once
+    pub fn no_retry() -> Self {
+        RetryPolicy {
+            max_attempts: NonZeroU32::MIN,
+            ..Default::default()
+        }
+    }
+
+    /// The delay before the `retry`th retry (starting at 0)
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+
+        if self.jitter {
+            delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+        } else {
+            delay
+        }
+    }
+}
+
+/// Run `op` until it succeeds, fails with an error that is not retryable,
+/// or the attempts allowed by `policy` are exhausted
+///
+/// `is_retryable` decides which errors are considered transient,
+/// e.g. network errors rather than authentication failures.
+/// The error of the last attempt is returned.
+pub fn retry<T, E, F>(
+    policy: &RetryPolicy,
+    is_retryable: impl Fn(&E) -> bool,
+    mut op: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Result<T, E>,
+{
+    let mut retry = 0;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) if retry + 1 < policy.max_attempts.get() && is_retryable(&e) => {
+                let delay = policy.delay(retry);
+                debug!("attempt {} failed, retrying in {delay:?}", retry + 1);
+                std::thread::sleep(delay);
+                retry += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The state of a [CircuitBreaker] for a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Operations are run as usual
+    Closed,
+    /// Too many consecutive failures, operations are rejected without being run
+    Open,
+    /// The reset timeout has passed since the circuit opened,
+    /// the next operation is run as a trial
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    consecutive_failures: u32,
+    opened_at: Option<DateTime<Utc>>,
+}
+
+/// Stops calling a host after too many consecutive failures
+///
+/// After `failure_threshold` consecutive failures the circuit for a host opens
+/// and operations are rejected with [CircuitBreakerError::Open].
+/// Once `reset_timeout` has passed, a single trial operation is let through:
+/// if it succeeds the circuit closes again, otherwise it stays open
+/// for another `reset_timeout`.
+///
+/// Hosts are tracked independently.
+/// Timeouts are measured with the [SharedClock] passed to [CircuitBreaker::new].
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: NonZeroU32,
+    reset_timeout: Duration,
+    clock: SharedClock,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+#[derive(Debug, Error)]
+pub enum CircuitBreakerError<E> {
+    #[error("too many consecutive failures talking to '{0}', not trying again for now")]
+    Open(String),
+    #[error(transparent)]
+    Operation(E),
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: NonZeroU32, reset_timeout: Duration, clock: SharedClock) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            reset_timeout,
+            clock,
+            hosts: Default::default(),
+        }
+    }
+
+    /// The current state of the circuit for `host`
+    pub fn state(&self, host: &str) -> CircuitState {
+        let hosts = self.hosts.lock().unwrap();
+        let Some(opened_at) = hosts.get(host).and_then(|state| state.opened_at) else {
+            return CircuitState::Closed;
+        };
+
+        // a clock that went backwards keeps the circuit open
+        let timed_out = (self.clock.now() - opened_at)
+            .to_std()
+            .map_or(false, |elapsed| elapsed >= self.reset_timeout);
+        if timed_out {
+            CircuitState::HalfOpen
+        } else {
+            CircuitState::Open
+        }
+    }
+
+    /// Run `op` against `host`, unless the circuit for `host` is open
+    pub fn call<T, E>(
+        &self,
+        host: &str,
+        op: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, CircuitBreakerError<E>> {
+        match self.state(host) {
+            CircuitState::Closed => {},
+            CircuitState::Open => return Err(CircuitBreakerError::Open(host.to_string())),
+            CircuitState::HalfOpen => {
+                // reopen while the trial is running,
+                // so concurrent callers are rejected rather than piling on
+                debug!("circuit for '{host}' is half open, running trial");
+                let mut hosts = self.hosts.lock().unwrap();
+                hosts.entry(host.to_string()).or_default().opened_at = Some(self.clock.now());
+            },
+        }
+
+        match op() {
+            Ok(value) => {
+                self.record_success(host);
+                Ok(value)
+            },
+            Err(e) => {
+                self.record_failure(host);
+                Err(CircuitBreakerError::Operation(e))
+            },
+        }
+    }
+
+    /// Close the circuit for `host` and reset its failure count
+    pub fn record_success(&self, host: &str) {
+        self.hosts.lock().unwrap().remove(host);
+    }
+
+    /// Count a failure for `host`, opening the circuit if the threshold is reached
+    pub fn record_failure(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures >= self.failure_threshold.get() {
+            if state.opened_at.is_none() {
+                debug!("too many failures talking to '{host}', opening circuit");
+            }
+            state.opened_at = Some(self.clock.now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::utils::clock::{system_clock, MockClock};
+
+    fn immediate_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: NonZeroU32::new(max_attempts).unwrap(),
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            jitter: false,
+        }
+    }
+
+    #[test]
+    fn retry_until_success() {
+        let attempts = Cell::new(0);
+        let result: Result<_, ()> = retry(
+            &immediate_policy(3),
+            |_| true,
+            || {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 {
+                    Err(())
+                } else {
+                    Ok(attempts.get())
+                }
+            },
+        );
+        assert_eq!(result, Ok(3));
+    }
+
+    #[test]
+    fn retry_gives_up_after_max_attempts() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = retry(
+            &immediate_policy(2),
+            |_| true,
+            || {
+                attempts.set(attempts.get() + 1);
+                Err(attempts.get())
+            },
+        );
+        assert_eq!(result, Err(2));
+    }
+
+    #[test]
+    fn retry_stops_on_permanent_error() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = retry(
+            &immediate_policy(5),
+            |e| *e != "permanent",
+            || {
+                attempts.set(attempts.get() + 1);
+                Err("permanent")
+            },
+        );
+        assert_eq!(result, Err("permanent"));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn retry_delay_is_bounded() {
+        let policy = RetryPolicy {
+            max_attempts: NonZeroU32::new(10).unwrap(),
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            jitter: false,
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(2), Duration::from_millis(300));
+        assert_eq!(policy.delay(64), Duration::from_millis(300));
+
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(
+            NonZeroU32::new(2).unwrap(),
+            Duration::from_secs(3600),
+            system_clock(),
+        );
+
+        for _ in 0..2 {
+            assert!(matches!(
+                breaker.call("example.com", || Err::<(), _>(())),
+                Err(CircuitBreakerError::Operation(()))
+            ));
+        }
+        assert_eq!(breaker.state("example.com"), CircuitState::Open);
+
+        let ran = Cell::new(false);
+        let result = breaker.call("example.com", || {
+            ran.set(true);
+            Ok::<_, ()>(())
+        });
+        assert!(matches!(result, Err(CircuitBreakerError::Open(_))));
+        assert!(!ran.get());
+
+        // other hosts are unaffected
+        assert_eq!(breaker.state("other.example.com"), CircuitState::Closed);
+        assert!(breaker
+            .call("other.example.com", || Ok::<_, ()>(()))
+            .is_ok());
+    }
+
+    #[test]
+    fn breaker_success_resets_failure_count() {
+        let breaker = CircuitBreaker::new(
+            NonZeroU32::new(2).unwrap(),
+            Duration::from_secs(3600),
+            system_clock(),
+        );
+
+        breaker.record_failure("example.com");
+        breaker.record_success("example.com");
+        breaker.record_failure("example.com");
+        assert_eq!(breaker.state("example.com"), CircuitState::Closed);
+    }
+
+    #[test]
+    fn breaker_half_opens_after_timeout() {
+        let timeout = Duration::from_secs(60);
+        let clock = Arc::new(MockClock::new(Utc.timestamp_opt(1_700_000_000, 0).unwrap()));
+        let breaker = CircuitBreaker::new(NonZeroU32::MIN, timeout, clock.clone());
+
+        breaker.record_failure("example.com");
+        assert_eq!(breaker.state("example.com"), CircuitState::Open);
+
+        clock.advance(timeout - Duration::from_secs(1));
+        assert_eq!(breaker.state("example.com"), CircuitState::Open);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(breaker.state("example.com"), CircuitState::HalfOpen);
+
+        // a failed trial opens the circuit again
+        assert!(matches!(
+            breaker.call("example.com", || Err::<(), _>(())),
+            Err(CircuitBreakerError::Operation(()))
+        ));
+        assert_eq!(breaker.state("example.com"), CircuitState::Open);
+
+        clock.advance(timeout);
+        assert_eq!(breaker.state("example.com"), CircuitState::HalfOpen);
+
+        // a successful trial closes it
+        assert!(breaker.call("example.com", || Ok::<_, ()>(())).is_ok());
+        assert_eq!(breaker.state("example.com"), CircuitState::Closed);
+    }
+}
@@ -18,9 +18,32 @@ impl<const V: u8> Debug for Version<V> {
     }
 }
 
-#[derive(Debug, Error)]
-#[error("Invalid version")]
-struct VersionError;
+impl<const V: u8> Version<V> {
+    /// Check that a version number read from a file
+    /// matches the version `V` supported by this type.
+    pub fn check(found: u8) -> Result<Self, VersionError> {
+        if found == V {
+            Ok(Version::<V>)
+        } else {
+            Err(VersionError {
+                found,
+                supported: V,
+            })
+        }
+    }
+}
+
+/// A version number did not match the version supported by [Version]
+///
+/// Data written by a newer version of flox will usually fail with this error.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("unsupported version {found} (expected version {supported})")]
+pub struct VersionError {
+    /// The version that was found
+    pub found: u8,
+    /// The version supported by this type
+    pub supported: u8,
+}
 
 impl<const V: u8> Serialize for Version<V> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -37,11 +60,7 @@ impl<'de, const V: u8> Deserialize<'de> for Version<V> {
         D: serde::Deserializer<'de>,
     {
         let value = u8::deserialize(deserializer)?;
-        if value == V {
-            Ok(Version::<V>)
-        } else {
-            Err(serde::de::Error::custom(VersionError))
-        }
+        Version::<V>::check(value).map_err(serde::de::Error::custom)
     }
 }
 
@@ -113,4 +132,16 @@ mod tests {
         }))
         .expect_err("Shouldn't parse wrong version");
     }
+
+    #[test]
+    fn check_reports_found_and_supported() {
+        assert_eq!(Version::<1>::check(1), Ok(Version::<1>));
+        assert_eq!(
+            Version::<1>::check(2),
+            Err(VersionError {
+                found: 2,
+                supported: 1
+            })
+        );
+    }
 }
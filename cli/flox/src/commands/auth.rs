@@ -24,7 +24,8 @@ use serde::Serialize;
 use url::Url;
 
 use crate::commands::general::update_config;
-use crate::config::Config;
+use crate::config::token_storage::{write_keyring, TokenStorage};
+use crate::config::{Config, FloxConfig};
 use crate::subcommand_metric;
 use crate::utils::dialog::{Checkpoint, Dialog};
 use crate::utils::message;
@@ -189,18 +190,18 @@ impl Auth {
 
         match self {
             Auth::Login => {
-                login_flox(&mut flox).await?;
+                login_flox(&mut flox, &config.flox).await?;
                 Ok(())
             },
             Auth::Logout => {
                 create_oauth_client()?;
 
-                if config.flox.floxhub_token.is_none() {
+                if !config.is_authenticated() {
                     message::warning("You are not logged in");
                     return Ok(());
                 }
 
-                update_config::<String>(&flox.config_dir, &flox.temp_dir, "floxhub_token", None)
+                store_token(&flox, &config.flox, None)
                     .context("Could not remove token from user config")?;
 
                 message::updated("Logout successful");
@@ -208,7 +209,7 @@ impl Auth {
                 Ok(())
             },
             Auth::User => {
-                let token = config.flox.floxhub_token.context("You are not logged in")?;
+                let token = flox.floxhub_token.context("You are not logged in")?;
                 let handle = token.handle().context("Could not get user details")?;
                 println!("{handle}");
                 Ok(())
@@ -217,31 +218,54 @@ impl Auth {
     }
 }
 
+/// Store `token` for the active profile in the configured [TokenStorage],
+/// or remove the stored token if `token` is [None]
+///
+/// If the keychain is unavailable, the token is stored in the config file instead.
+/// Once the keychain holds the token, it is removed from the config file.
+fn store_token(flox: &Flox, config: &FloxConfig, token: Option<&FloxhubToken>) -> Result<()> {
+    let key = config.floxhub_token_key();
+
+    if config.token_storage == TokenStorage::Keyring {
+        match write_keyring(config.active_profile.as_deref(), token) {
+            Ok(()) => {
+                if let Err(e) =
+                    update_config::<String>(&flox.config_dir, &flox.temp_dir, &key, None)
+                {
+                    debug!("no token removed from config file: {e}");
+                }
+                return Ok(());
+            },
+            Err(e) => message::warning(format!(
+                "Could not access the keychain, using the config file instead: {e}"
+            )),
+        }
+    }
+
+    update_config(&flox.config_dir, &flox.temp_dir, &key, token)
+}
+
 /// run the login flow
 ///
-/// * updates the config file with the received token
+/// * stores the received token for the active profile, see [store_token]
 /// * updates the floxhub_token field in the config struct
-pub async fn login_flox(flox: &mut Flox) -> Result<()> {
+pub async fn login_flox(flox: &mut Flox, config: &FloxConfig) -> Result<()> {
     let client = create_oauth_client()?;
     let cred = authorize(client, flox.floxhub.base_url())
         .await
         .context("Could not authorize via oauth")?;
 
     debug!("Credentials received: {cred:#?}");
-    debug!("Writing token to config");
+    debug!("Storing token");
 
     // set the token in the runtime config
-    let token = flox.floxhub_token.insert(FloxhubToken::new(cred.token));
+    let token = flox
+        .floxhub_token
+        .insert(FloxhubToken::new(cred.token))
+        .clone();
     let handle = token.handle().context("Could not get user details")?;
 
-    // write the token to the config file
-    update_config(
-        &flox.config_dir,
-        &flox.temp_dir,
-        "floxhub_token",
-        Some(token),
-    )
-    .context("Could not write token to config")?;
+    store_token(flox, config, Some(&token)).context("Could not store token")?;
 
     message::updated("Authentication complete");
     message::updated(format!("Logged in as {handle}"));
@@ -1376,7 +1376,7 @@ pub struct Push {
 }
 
 impl Push {
-    pub async fn handle(self, mut flox: Flox) -> Result<()> {
+    pub async fn handle(self, config: Config, mut flox: Flox) -> Result<()> {
         subcommand_metric!("push");
 
         if flox.floxhub_token.is_none() {
@@ -1396,7 +1396,7 @@ impl Push {
 
             message::plain("You are not logged in to FloxHub. Logging in...");
 
-            auth::login_flox(&mut flox).await?;
+            auth::login_flox(&mut flox, &config.flox).await?;
         }
 
         let dir = self.dir.unwrap_or_else(|| std::env::current_dir().unwrap());
@@ -1414,6 +1414,8 @@ impl Push {
             EnvironmentPointer::Path(path_pointer) => {
                 let owner = if let Some(owner) = self.owner {
                     owner
+                } else if let Some(user) = &config.flox.floxmeta_user {
+                    EnvironmentOwner::from_str(user)?
                 } else {
                     EnvironmentOwner::from_str(
                         &flox
@@ -67,6 +67,9 @@ pub enum ConfigArgs {
     /// List the current values of all options
     #[bpaf(short, long)]
     List,
+    /// List where each configured option is set
+    #[bpaf(long)]
+    ShowOrigin,
     /// Reset all options to their default values without further confirmation
     #[bpaf(short, long)]
     Reset,
@@ -86,6 +89,12 @@ impl ConfigArgs {
         subcommand_metric!("config");
         match self {
             ConfigArgs::List => println!("{}", config.get(&[])?),
+            ConfigArgs::ShowOrigin => {
+                // values are not shown, they may contain secrets such as tokens
+                for (key, source) in config.sources()? {
+                    println!("{source}\t{key}");
+                }
+            },
             ConfigArgs::Reset => {
                 match fs::remove_file(&flox.config_dir.join(FLOX_CONFIG_FILE)).await {
                     Err(err) if err.kind() != io::ErrorKind::NotFound => {
@@ -27,6 +27,7 @@ use flox_rust_sdk::models::environment::{
 };
 use flox_rust_sdk::models::environment_ref;
 use flox_rust_sdk::nix::command_line::NixCommandLine;
+use flox_rust_sdk::utils::clock::system_clock;
 use indoc::{formatdoc, indoc};
 use log::{debug, info};
 use once_cell::sync::Lazy;
@@ -128,6 +129,10 @@ pub struct FloxArgs {
     #[bpaf(long, req_flag(()), many, map(vec_not_empty), hide)]
     pub debug: bool,
 
+    /// Override a config option for this invocation, see 'man flox-config'
+    #[bpaf(long("config-option"), argument("key=value"), many)]
+    pub config_options: Vec<String>,
+
     /// Print the version of the program
     #[allow(dead_code)] // fake arg, `--version` is checked for separately (see [Version])
     #[bpaf(long)]
@@ -151,8 +156,8 @@ impl FloxArgs {
         tokio::fs::create_dir_all(&config.flox.data_dir).await?;
 
         // prepare a temp dir for the run:
-        let process_dir = config.flox.cache_dir.join("process");
-        tokio::fs::create_dir_all(&process_dir).await?;
+        config.flox.ensure_writable_cache_dir()?;
+        let process_dir = config.flox.process_dir()?;
 
         // `temp_dir` will automatically be removed from disk when the function returns
         let temp_dir = TempDir::new_in(process_dir)?;
@@ -193,10 +198,6 @@ impl FloxArgs {
                 .map(|nix_config| &nix_config.access_tokens),
         )?;
 
-        let netrc_file = dirs::home_dir()
-            .expect("User must have a home directory")
-            .join(".netrc");
-
         let git_url_override = {
             if let Ok(env_set_host) = std::env::var("_FLOX_FLOXHUB_GIT_URL") {
                 message::warning(formatdoc! {"
@@ -210,7 +211,7 @@ impl FloxArgs {
             }
         };
 
-        let floxhub = Floxhub::new(
+        let mut floxhub = Floxhub::new(
             config
                 .flox
                 .floxhub_url
@@ -218,18 +219,25 @@ impl FloxArgs {
                 .unwrap_or_else(|| DEFAULT_FLOXHUB_URL.clone()),
             git_url_override,
         )?;
+        if let Some(api_url) = config.flox.floxhub_api_url.clone() {
+            floxhub = floxhub.with_api_url_override(api_url);
+        }
 
         let flox = Flox {
             cache_dir: config.flox.cache_dir.clone(),
             data_dir: config.flox.data_dir.clone(),
             config_dir: config.flox.config_dir.clone(),
             access_tokens,
-            netrc_file,
+            netrc_file: config.flox.netrc_file(),
             temp_dir: temp_dir_path.clone(),
-            system: env!("NIX_TARGET_SYSTEM").to_string(),
+            system: config.flox.system()?,
             uuid: init_uuid(&config.flox.data_dir).await?,
-            floxhub_token: config.flox.floxhub_token.clone(),
+            floxhub_token: config.flox.stored_floxhub_token(),
             floxhub,
+            floxmeta_reference: config.flox.floxmeta_reference.clone(),
+            git_timeout: config.flox.git_timeout(),
+            fetch_jobs: config.flox.fetch_jobs,
+            clock: system_clock(),
         };
 
         // Set the global Nix config via the environment variables in flox.default_args so that
@@ -386,9 +394,9 @@ enum SharingCommands {
     Containerize(#[bpaf(external(environment::containerize))] environment::Containerize),
 }
 impl SharingCommands {
-    async fn handle(self, _config: Config, flox: Flox) -> Result<()> {
+    async fn handle(self, config: Config, flox: Flox) -> Result<()> {
         match self {
-            SharingCommands::Push(args) => args.handle(flox).await?,
+            SharingCommands::Push(args) => args.handle(config, flox).await?,
             SharingCommands::Pull(args) => args.handle(flox).await?,
             SharingCommands::Containerize(args) => args.handle(flox).await?,
         }
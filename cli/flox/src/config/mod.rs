@@ -1,10 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{env, fs};
 
-use anyhow::{Context, Result};
-use config::{Config as HierarchicalConfig, Environment};
-use flox_rust_sdk::flox::{EnvironmentRef, FloxhubToken};
+use anyhow::{bail, Context, Result};
+use config::builder::{ConfigBuilder, DefaultState};
+use config::{Config as HierarchicalConfig, ConfigError, Environment, Source};
+use flox_rust_sdk::flox::{EnvironmentRef, FloxhubToken, FLOX_SYSTEMS};
 use itertools::{Either, Itertools};
 use log::{debug, trace};
 use once_cell::sync::OnceCell;
@@ -16,10 +19,13 @@ use url::Url;
 use xdg::BaseDirectories;
 
 use self::features::Features;
+use self::token_storage::TokenStorage;
+use crate::utils::message;
 
 /// Name of flox managed directories (config, data, cache)
 const FLOX_DIR_NAME: &str = "flox";
 const FLOX_CONFIG_DIR_VAR: &str = "FLOX_CONFIG_DIR";
+const FLOX_PROFILE_VAR: &str = "FLOX_PROFILE";
 pub const FLOX_CONFIG_FILE: &str = "flox.toml";
 
 #[derive(Clone, Debug, Deserialize, Default, Serialize)]
@@ -52,10 +58,18 @@ pub struct FloxConfig {
     /// Directory where flox should load its configuration file (default:
     /// `$XDG_CONFIG_HOME/flox`)
     pub config_dir: PathBuf,
+    /// Directory where flox should create temporary directories for each
+    /// invocation (default: `$cache_dir/process`)
+    pub temp_dir: Option<PathBuf>,
 
     /// Token to authenticate on FloxHub
     pub floxhub_token: Option<FloxhubToken>,
 
+    /// Where `flox auth login` stores the FloxHub token
+    /// (default: `file`, i.e. [Self::floxhub_token])
+    #[serde(default)]
+    pub token_storage: TokenStorage,
+
     /// How many items `flox search` should show by default
     pub search_limit: Option<u8>,
 
@@ -65,6 +79,254 @@ pub struct FloxConfig {
 
     /// The URL of the FloxHub instance to use
     pub floxhub_url: Option<Url>,
+
+    /// The URL of the FloxHub api,
+    /// for instances that serve it from a host not derived from [Self::floxhub_url]
+    pub floxhub_api_url: Option<Url>,
+
+    /// How many environments to fetch concurrently
+    /// (default: the number of available CPUs)
+    pub fetch_jobs: Option<NonZeroUsize>,
+
+    /// The system flox should operate on,
+    /// e.g. to use remote builders for a different architecture
+    /// (default: the system flox was built for)
+    pub system: Option<String>,
+
+    /// Accept a configured system that is not supported by flox,
+    /// e.g. to experiment with new architectures
+    #[serde(default)]
+    pub allow_unsupported_system: bool,
+
+    /// Path to a netrc file used to authenticate with substituters
+    /// (default: `~/.netrc`)
+    pub netrc: Option<PathBuf>,
+
+    /// A local git repository to borrow objects from when cloning floxmeta,
+    /// e.g. a shared mirror on a fleet of machines
+    pub floxmeta_reference: Option<PathBuf>,
+
+    /// Seconds after which a git operation is aborted
+    /// (default: no timeout)
+    ///
+    /// `0` is rejected rather than aborting every operation immediately,
+    /// leave this unset to disable the timeout.
+    pub git_timeout: Option<NonZeroU64>,
+
+    /// Named sets of FloxHub settings, e.g. for separate work and personal accounts
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+
+    /// The FloxHub user whose floxmeta repository new environments are pushed to
+    /// (default: the user [Self::floxhub_token] belongs to)
+    pub floxmeta_user: Option<String>,
+
+    /// The profile in [Self::profiles] to use,
+    /// overriding the top level FloxHub settings (default: none)
+    ///
+    /// `FLOX_PROFILE` takes precedence over this setting.
+    pub active_profile: Option<String>,
+}
+
+/// FloxHub settings of a profile, see [FloxConfig::profiles]
+///
+/// Unset URLs fall back to the top level settings.
+/// The token and user belong to the account of the profile
+/// and never fall back to those of another account.
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub struct ProfileConfig {
+    /// Token to authenticate on FloxHub,
+    /// written by `flox auth login` while the profile is active
+    pub floxhub_token: Option<FloxhubToken>,
+    /// The URL of the FloxHub instance to use
+    pub floxhub_url: Option<Url>,
+    /// The FloxHub user whose floxmeta repository new environments are pushed to
+    pub floxmeta_user: Option<String>,
+}
+
+impl FloxConfig {
+    /// Return the configured system or the system flox was built for
+    ///
+    /// Fails if the configured system is not supported by flox,
+    /// unless [Self::allow_unsupported_system] is set.
+    pub fn system(&self) -> Result<String> {
+        let Some(system) = &self.system else {
+            return Ok(env!("NIX_TARGET_SYSTEM").to_string());
+        };
+
+        if !FLOX_SYSTEMS.contains(&system.as_str()) {
+            if self.allow_unsupported_system {
+                debug!("using unsupported system '{system}'");
+                return Ok(system.clone());
+            }
+            bail!(
+                "Unsupported system '{system}', expected one of: {}",
+                FLOX_SYSTEMS.join(", ")
+            );
+        }
+
+        Ok(system.clone())
+    }
+
+    /// Override the FloxHub settings with those of the [Self::active_profile]
+    ///
+    /// Fails if a profile name is invalid or the active profile is not defined.
+    pub fn apply_profile(&mut self) -> Result<()> {
+        let is_valid_name = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+
+        if let Some(name) = self.profiles.keys().find(|name| !is_valid_name(name)) {
+            bail!(
+                "Invalid profile name '{name}', profile names may only contain alphanumeric characters, '-' and '_'"
+            );
+        }
+
+        let Some(active) = &self.active_profile else {
+            return Ok(());
+        };
+
+        let Some(profile) = self.profiles.get(active) else {
+            bail!(
+                "Profile '{active}' is not defined, available profiles: {}",
+                self.profiles.keys().join(", ")
+            );
+        };
+
+        debug!("using profile '{active}'");
+        self.floxhub_token = profile.floxhub_token.clone();
+        self.floxmeta_user = profile.floxmeta_user.clone();
+        if let Some(url) = &profile.floxhub_url {
+            self.floxhub_url = Some(url.clone());
+        }
+        Ok(())
+    }
+
+    /// Return the FloxHub token of the [Self::active_profile]
+    /// from the configured [TokenStorage]
+    ///
+    /// If the keychain is configured but unavailable, [Self::floxhub_token] is used.
+    pub fn stored_floxhub_token(&self) -> Option<FloxhubToken> {
+        token_storage::resolve_token(self.token_storage, self.floxhub_token.as_ref(), || {
+            token_storage::read_keyring(self.active_profile.as_deref())
+        })
+    }
+
+    /// The key in the config file holding the FloxHub token of the [Self::active_profile]
+    pub fn floxhub_token_key(&self) -> String {
+        match &self.active_profile {
+            Some(profile) => format!("profiles.{profile}.floxhub_token"),
+            None => "floxhub_token".to_string(),
+        }
+    }
+
+    /// Return the configured netrc file or `~/.netrc`
+    ///
+    /// Returns [None] if the file does not exist.
+    pub fn netrc_file(&self) -> Option<PathBuf> {
+        self.netrc
+            .clone()
+            .or_else(|| dirs::home_dir().map(|home| home.join(".netrc")))
+            .filter(|netrc| netrc.exists())
+    }
+
+    /// Return the configured git timeout, if any
+    pub fn git_timeout(&self) -> Option<Duration> {
+        self.git_timeout
+            .map(|timeout| Duration::from_secs(timeout.get()))
+    }
+
+    /// Ensure that [Self::cache_dir] is writable
+    ///
+    /// In sandboxed or immutable deployments the cache dir may be read-only.
+    /// Rather than failing every command,
+    /// fall back to a per-user directory in the system temp dir and warn about it.
+    /// As the name of that directory is predictable,
+    /// it is only used if it is owned by the current user, see [create_private_dir].
+    /// Fails with an error naming the configured cache dir
+    /// if the fallback is not writable either.
+    pub fn ensure_writable_cache_dir(&mut self) -> Result<()> {
+        let Err(err) = check_writable(&self.cache_dir) else {
+            return Ok(());
+        };
+
+        let fallback = env::temp_dir().join(format!("flox-cache-{}", nix::unistd::getuid()));
+        create_private_dir(&fallback).with_context(|| {
+            format!(
+                "Cache directory '{}' is not writable ({err}), and neither is the fallback '{}'",
+                self.cache_dir.display(),
+                fallback.display()
+            )
+        })?;
+
+        message::warning(format!(
+            "Cache directory '{}' is not writable ({err}), using '{}' instead",
+            self.cache_dir.display(),
+            fallback.display()
+        ));
+        self.cache_dir = fallback;
+        Ok(())
+    }
+
+    /// Return the directory to create per invocation temporary directories in
+    ///
+    /// This is [Self::temp_dir] if set, or `process` in [Self::cache_dir].
+    /// The directory is created if necessary.
+    /// Fails if it is not writable.
+    pub fn process_dir(&self) -> Result<PathBuf> {
+        let process_dir = self
+            .temp_dir
+            .clone()
+            .unwrap_or_else(|| self.cache_dir.join("process"));
+
+        check_writable(&process_dir).with_context(|| {
+            format!(
+                "Temporary directory '{}' is not writable",
+                process_dir.display()
+            )
+        })?;
+        Ok(process_dir)
+    }
+}
+
+/// Check that `dir` exists or can be created, and that files can be created in it
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    tempfile::tempfile_in(dir)?;
+    Ok(())
+}
+
+/// Create `dir` accessible only by the current user, or reuse it if it is ours
+///
+/// `dir` is expected to be in a shared location such as `/tmp`,
+/// where another user could have created it first.
+/// Refuses to use `dir` if it is a symlink or owned by a different user.
+/// An existing directory is restricted to mode `0700`.
+fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::fs::DirBuilder;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {},
+        result => result?,
+    }
+
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        bail!("'{}' is not a directory", dir.display());
+    }
+    if metadata.uid() != nix::unistd::getuid().as_raw() {
+        bail!("'{}' is owned by a different user", dir.display());
+    }
+    if metadata.mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+
+    check_writable(dir)?;
+    Ok(())
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -82,6 +344,7 @@ pub struct NixConfig {
 }
 
 pub mod features;
+pub mod token_storage;
 
 /// Error returned by [`Config::get()`]
 #[derive(Debug, Error)]
@@ -113,92 +376,226 @@ pub enum ReadWriteError {
     Persist(#[from] PersistError),
 }
 
+/// Config values set with `--config-option`, see [Config::set_cli_overrides]
+static CLI_OVERRIDES: OnceCell<Vec<(String, String)>> = OnceCell::new();
+
 impl Config {
     /// Creates a raw [Config] object and caches it for the lifetime of the program
     fn raw_config<'a>() -> Result<&'a HierarchicalConfig> {
         static INSTANCE: OnceCell<HierarchicalConfig> = OnceCell::new();
         INSTANCE.get_or_try_init(|| {
-            let flox_dirs = BaseDirectories::with_prefix(FLOX_DIR_NAME)?;
-
-            let cache_dir = flox_dirs.get_cache_home();
-            let data_dir = flox_dirs.get_data_home();
-
-            let config_dir = match env::var(FLOX_CONFIG_DIR_VAR) {
-                Ok(v) => {
-                    debug!("`${FLOX_CONFIG_DIR_VAR}` set: {v}");
-                    fs::create_dir_all(&v)
-                        .context(format!("Could not create config directory: {v:?}"))?;
-                    v.into()
-                },
-                Err(_) => {
-                    let config_dir = flox_dirs.get_config_home();
-                    debug!("`${FLOX_CONFIG_DIR_VAR}` not set, using {config_dir:?}");
-                    fs::create_dir_all(&config_dir)
-                        .context(format!("Could not create config directory: {config_dir:?}"))?;
-                    let config_dir = config_dir
-                        .canonicalize()
-                        .context("Could not canonicalize config directory '{config_dir:?}'")?;
-                    env::set_var(FLOX_CONFIG_DIR_VAR, &config_dir);
-                    config_dir
-                },
-            };
-
-            let mut builder = HierarchicalConfig::builder()
-                .set_default("default_substituter", "https://cache.floxdev.com/")?
-                .set_default("cache_dir", cache_dir.to_str().unwrap())?
-                .set_default("data_dir", data_dir.to_str().unwrap())?
+            let ConfigLayers {
+                config_dir, layers, ..
+            } = Self::layers()?;
+
+            let builder = layers
+                .iter()
+                .try_fold(HierarchicalConfig::builder(), |builder, (_, add_layer)| {
+                    add_layer(builder)
+                })?
                 // Config dir is added to the config for completeness;
                 // the config file cannot change the config dir.
                 .set_override("config_dir", config_dir.to_str().unwrap())?;
 
-            // read from /etc
-            builder = builder.add_source(
-                config::File::from(PathBuf::from("/etc").join(FLOX_CONFIG_FILE))
-                    .format(config::FileFormat::Toml)
-                    .required(false),
-            );
+            let final_config = builder.build()?;
 
-            // look for files in XDG_CONFIG_DIRS locations
-            for file in flox_dirs.find_config_files(FLOX_CONFIG_FILE) {
-                builder =
-                    builder.add_source(config::File::from(file).format(config::FileFormat::Toml));
-            }
+            Ok(final_config)
+        })
+    }
 
-            // Add explicit FLOX_CONFIG_DIR file last
-            builder = builder.add_source(
-                config::File::from(config_dir.join(FLOX_CONFIG_FILE))
-                    .format(config::FileFormat::Toml)
-                    .required(false),
-            );
+    /// Set the `<key>=<value>` overrides passed on the command line
+    ///
+    /// Must be called before the config is first read to take effect,
+    /// later calls are ignored.
+    pub fn set_cli_overrides(overrides: &[String]) -> Result<()> {
+        let overrides = overrides
+            .iter()
+            .map(|option| {
+                let (key, value) = option
+                    .split_once('=')
+                    .with_context(|| format!("Expected '<key>=<value>', found '{option}'"))?;
+                Ok((key.to_string(), value.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let _ = CLI_OVERRIDES.set(overrides);
+        Ok(())
+    }
 
-            // override via env variables
-            let mut flox_envs = env::vars()
-                .filter_map(|(k, v)| k.strip_prefix("FLOX_").map(|k| (k.to_owned(), v)))
-                .collect::<Vec<_>>();
-
-            let builder = builder
-                .add_source(mk_environment(&mut flox_envs, "NIX"))
-                .add_source(mk_environment(&mut flox_envs, "GITHUB"))
-                .add_source(mk_environment(&mut flox_envs, "FEATURES"))
-                .add_source(
-                    Environment::default()
-                        .source(Some(HashMap::from_iter(flox_envs)))
-                        .try_parsing(true),
-                );
+    /// Determine the config dir and the layers of configuration
+    /// and cache them for the lifetime of the program
+    fn layers<'a>() -> Result<&'a ConfigLayers> {
+        static INSTANCE: OnceCell<ConfigLayers> = OnceCell::new();
+        INSTANCE.get_or_try_init(Self::read_layers)
+    }
 
-            let final_config = builder.build()?;
+    fn read_layers() -> Result<ConfigLayers> {
+        let flox_dirs = BaseDirectories::with_prefix(FLOX_DIR_NAME)?;
 
-            Ok(final_config)
+        let cache_dir = flox_dirs.get_cache_home();
+        let data_dir = flox_dirs.get_data_home();
+
+        let config_dir_source = match env::var_os(FLOX_CONFIG_DIR_VAR) {
+            Some(_) => ConfigSource::Environment,
+            None => ConfigSource::Default,
+        };
+        let config_dir = match env::var(FLOX_CONFIG_DIR_VAR) {
+            Ok(v) => {
+                debug!("`${FLOX_CONFIG_DIR_VAR}` set: {v}");
+                fs::create_dir_all(&v)
+                    .context(format!("Could not create config directory: {v:?}"))?;
+                v.into()
+            },
+            Err(_) => {
+                let config_dir = flox_dirs.get_config_home();
+                debug!("`${FLOX_CONFIG_DIR_VAR}` not set, using {config_dir:?}");
+                fs::create_dir_all(&config_dir)
+                    .context(format!("Could not create config directory: {config_dir:?}"))?;
+                let config_dir = config_dir
+                    .canonicalize()
+                    .context("Could not canonicalize config directory '{config_dir:?}'")?;
+                env::set_var(FLOX_CONFIG_DIR_VAR, &config_dir);
+                config_dir
+            },
+        };
+
+        let mut layers: Vec<(ConfigSource, ConfigLayer)> = Vec::new();
+
+        layers.push((
+            ConfigSource::Default,
+            Box::new(move |builder| {
+                builder
+                    .set_default("default_substituter", "https://cache.floxdev.com/")?
+                    .set_default("cache_dir", cache_dir.to_str().unwrap())?
+                    .set_default("data_dir", data_dir.to_str().unwrap())
+            }),
+        ));
+
+        // read from /etc
+        let etc_config_file = PathBuf::from("/etc").join(FLOX_CONFIG_FILE);
+        layers.push(file_layer(etc_config_file, false));
+
+        // look for files in XDG_CONFIG_DIRS locations
+        for file in flox_dirs.find_config_files(FLOX_CONFIG_FILE) {
+            layers.push(file_layer(file, true));
+        }
+
+        // Add explicit FLOX_CONFIG_DIR file last
+        layers.push(file_layer(config_dir.join(FLOX_CONFIG_FILE), false));
+
+        // override via env variables
+        // `FLOX_PROFILE` selects the active profile, see [Config::parse]
+        let mut flox_envs = env::vars()
+            .filter(|(k, _)| k != FLOX_PROFILE_VAR)
+            .filter_map(|(k, v)| k.strip_prefix("FLOX_").map(|k| (k.to_owned(), v)))
+            .collect::<Vec<_>>();
+
+        let environments = [
+            mk_environment(&mut flox_envs, "NIX"),
+            mk_environment(&mut flox_envs, "GITHUB"),
+            mk_environment(&mut flox_envs, "FEATURES"),
+            Environment::default()
+                .source(Some(HashMap::from_iter(flox_envs)))
+                .try_parsing(true),
+        ];
+        layers.push((
+            ConfigSource::Environment,
+            Box::new(move |builder| {
+                Ok(environments
+                    .iter()
+                    .cloned()
+                    .fold(builder, |builder, environment| {
+                        builder.add_source(environment)
+                    }))
+            }),
+        ));
+
+        // override via `--config-option`
+        let overrides = CLI_OVERRIDES.get().cloned().unwrap_or_default();
+        layers.push((
+            ConfigSource::CommandLine,
+            Box::new(move |builder| {
+                overrides.iter().try_fold(builder, |builder, (key, value)| {
+                    builder.set_override(key.as_str(), value.as_str())
+                })
+            }),
+        ));
+
+        Ok(ConfigLayers {
+            config_dir,
+            config_dir_source,
+            layers,
         })
     }
 
+    /// Report which layer provided the final value of each configured key
+    ///
+    /// Keys are dotted paths, e.g. `floxhub_url` or `nix.access_tokens.github.com`.
+    /// Only keys that are set by any layer are reported,
+    /// keys left to the defaults of [Config] itself are absent.
+    /// FloxHub settings taken from the active profile of this config
+    /// are reported with the source of the profile setting.
+    ///
+    /// **intended for human consumtion/intospection of config only**
+    pub fn sources(&self) -> Result<BTreeMap<String, ConfigSource>> {
+        let ConfigLayers {
+            config_dir_source,
+            layers,
+            ..
+        } = Self::layers()?;
+        let mut sources = sources_of(layers)?;
+
+        // the config dir is determined before any layer is read
+        sources.insert("config_dir".to_string(), config_dir_source.clone());
+
+        if env::var_os(FLOX_PROFILE_VAR).is_some() {
+            sources.insert("active_profile".to_string(), ConfigSource::Environment);
+        }
+        self.apply_profile_sources(&mut sources);
+
+        Ok(sources)
+    }
+
+    /// Attribute the settings [FloxConfig::apply_profile] took from the active profile
+    /// to the layer that set them in the profile
+    fn apply_profile_sources(&self, sources: &mut BTreeMap<String, ConfigSource>) {
+        let Some(profile) = &self.flox.active_profile else {
+            return;
+        };
+
+        for key in ["floxhub_token", "floxmeta_user", "floxhub_url"] {
+            match sources.get(&format!("profiles.{profile}.{key}")).cloned() {
+                Some(source) => {
+                    sources.insert(key.to_string(), source);
+                },
+                // the url falls back to the top level setting
+                None if key == "floxhub_url" => {},
+                None => {
+                    sources.remove(key);
+                },
+            }
+        }
+    }
+
+    /// Whether a floxhub token is configured
+    ///
+    /// Without a token flox operates anonymously,
+    /// which is sufficient to pull public environments.
+    /// Pushing environments requires authentication.
+    pub fn is_authenticated(&self) -> bool {
+        self.flox.stored_floxhub_token().is_some()
+    }
+
     /// Creates a [Config] from the environment and config file
     pub fn parse() -> Result<Config> {
         let final_config = Self::raw_config()?;
-        let cli_confg: Config = final_config
+        let mut cli_confg: Config = final_config
             .to_owned()
             .try_deserialize()
             .context("Could not parse config")?;
+        if let Ok(profile) = env::var(FLOX_PROFILE_VAR) {
+            cli_confg.flox.active_profile = Some(profile);
+        }
+        cli_confg.flox.apply_profile()?;
         Ok(cli_confg)
     }
 
@@ -340,6 +737,85 @@ fn mk_environment(envs: &mut Vec<(String, String)>, prefix: &str) -> Environment
     environment
 }
 
+/// The layer of configuration a value was read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// Built-in defaults
+    Default,
+    /// A config file
+    File(PathBuf),
+    /// `FLOX_*` environment variables
+    Environment,
+    /// `--config-option` arguments
+    CommandLine,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(path) => write!(f, "file {}", path.display()),
+            ConfigSource::Environment => write!(f, "environment"),
+            ConfigSource::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Adds the sources of a layer of configuration to a config builder
+type ConfigLayer = Box<
+    dyn Fn(ConfigBuilder<DefaultState>) -> Result<ConfigBuilder<DefaultState>, ConfigError>
+        + Send
+        + Sync,
+>;
+
+struct ConfigLayers {
+    config_dir: PathBuf,
+    config_dir_source: ConfigSource,
+    /// Layers of configuration in increasing precedence
+    layers: Vec<(ConfigSource, ConfigLayer)>,
+}
+
+fn file_layer(path: PathBuf, required: bool) -> (ConfigSource, ConfigLayer) {
+    let file = config::File::from(path.clone())
+        .format(config::FileFormat::Toml)
+        .required(required);
+    (
+        ConfigSource::File(path),
+        Box::new(move |builder| Ok(builder.add_source(file.clone()))),
+    )
+}
+
+/// Determine for each key the last layer that sets it
+fn sources_of(layers: &[(ConfigSource, ConfigLayer)]) -> Result<BTreeMap<String, ConfigSource>> {
+    fn collect_keys(
+        prefix: Option<&str>,
+        table: config::Map<String, config::Value>,
+    ) -> Vec<String> {
+        table
+            .into_iter()
+            .flat_map(|(key, value)| {
+                let key = match prefix {
+                    Some(prefix) => format!("{prefix}.{key}"),
+                    None => key,
+                };
+                match value.into_table() {
+                    Ok(table) if !table.is_empty() => collect_keys(Some(&key), table),
+                    _ => vec![key],
+                }
+            })
+            .collect()
+    }
+
+    let mut sources = BTreeMap::new();
+    for (source, add_layer) in layers {
+        let layer = add_layer(HierarchicalConfig::builder())?.build()?;
+        for key in collect_keys(None, layer.collect()?) {
+            sources.insert(key, source.clone());
+        }
+    }
+    Ok(sources)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -380,6 +856,326 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_profile_by_env() {
+        let tempdir = tempfile::tempdir().unwrap();
+        fs::write(tempdir.path().join(FLOX_CONFIG_FILE), indoc! {"
+            active_profile = 'personal'
+
+            [profiles.personal]
+            floxhub_url = 'https://personal.example.com'
+
+            [profiles.work]
+            floxhub_url = 'https://work.example.com'
+        "})
+        .unwrap();
+
+        let tempdir_path = tempdir.path().as_os_str().to_string_lossy();
+        temp_env::with_vars(
+            [
+                ("HOME", Some(tempdir_path.as_ref())),
+                (FLOX_CONFIG_DIR_VAR, Some(tempdir_path.as_ref())),
+                (FLOX_PROFILE_VAR, Some("work")),
+            ],
+            || {
+                let config = Config::parse().unwrap();
+                assert_eq!(config.flox.active_profile.as_deref(), Some("work"));
+                assert_eq!(
+                    config.flox.floxhub_url.as_ref().map(Url::as_str),
+                    Some("https://work.example.com/")
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn test_sources_of_overlapping_layers() {
+        let string_layer =
+            |source: ConfigSource, content: &'static str| -> (ConfigSource, ConfigLayer) {
+                (
+                    source,
+                    Box::new(move |builder| {
+                        Ok(builder
+                            .add_source(config::File::from_str(content, config::FileFormat::Toml)))
+                    }),
+                )
+            };
+
+        let layers: Vec<(ConfigSource, ConfigLayer)> = vec![
+            (
+                ConfigSource::Default,
+                Box::new(|builder| {
+                    builder
+                        .set_default("cache_dir", "/default/cache")?
+                        .set_default("data_dir", "/default/data")
+                }),
+            ),
+            string_layer(ConfigSource::File("/etc/flox.toml".into()), indoc! {"
+                    data_dir = '/etc/data'
+                    search_limit = 10
+                "}),
+            string_layer(
+                ConfigSource::File("/home/user/.config/flox/flox.toml".into()),
+                indoc! {"
+                    search_limit = 20
+
+                    [nix.access_tokens]
+                    'github.com' = 'token'
+                "},
+            ),
+            (
+                ConfigSource::Environment,
+                Box::new(|builder| {
+                    Ok(builder.add_source(
+                        Environment::default()
+                            .source(Some(HashMap::from([(
+                                "SEARCH_LIMIT".to_string(),
+                                "30".to_string(),
+                            )])))
+                            .try_parsing(true),
+                    ))
+                }),
+            ),
+            (
+                ConfigSource::CommandLine,
+                Box::new(|builder| builder.set_override("git_timeout", "5")),
+            ),
+        ];
+
+        let sources = sources_of(&layers).unwrap();
+        assert_eq!(
+            sources,
+            BTreeMap::from([
+                ("cache_dir".to_string(), ConfigSource::Default),
+                (
+                    "data_dir".to_string(),
+                    ConfigSource::File("/etc/flox.toml".into())
+                ),
+                (
+                    "nix.access_tokens.github.com".to_string(),
+                    ConfigSource::File("/home/user/.config/flox/flox.toml".into())
+                ),
+                ("git_timeout".to_string(), ConfigSource::CommandLine),
+                ("search_limit".to_string(), ConfigSource::Environment),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_apply_profile_sources() {
+        let file = ConfigSource::File("/home/user/.config/flox/flox.toml".into());
+        let mut sources = BTreeMap::from([
+            ("floxhub_token".to_string(), ConfigSource::Environment),
+            ("floxhub_url".to_string(), ConfigSource::Environment),
+            ("floxmeta_user".to_string(), file.clone()),
+            ("profiles.work.floxhub_token".to_string(), file.clone()),
+        ]);
+
+        let mut config = Config::default();
+        config.apply_profile_sources(&mut sources);
+        assert_eq!(sources["floxhub_token"], ConfigSource::Environment);
+
+        config.flox.active_profile = Some("work".to_string());
+        config.apply_profile_sources(&mut sources);
+        assert_eq!(sources["floxhub_token"], file);
+        assert_eq!(sources["floxhub_url"], ConfigSource::Environment);
+        // not inherited from the top level
+        assert!(!sources.contains_key("floxmeta_user"));
+    }
+
+    #[test]
+    fn test_git_timeout() {
+        let parse = |git_timeout: &str| {
+            serde_json::from_str::<FloxConfig>(&format!(
+                r#"{{"cache_dir": "", "data_dir": "", "config_dir": "", "git_timeout": {git_timeout}}}"#
+            ))
+        };
+
+        assert_eq!(
+            parse("5").unwrap().git_timeout(),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(parse("null").unwrap().git_timeout(), None);
+        assert!(parse("0").is_err());
+    }
+
+    #[test]
+    fn test_is_authenticated() {
+        let mut config = Config::default();
+        assert!(!config.is_authenticated());
+
+        config.flox.floxhub_token = Some(FloxhubToken::new("token".to_string()));
+        assert!(config.is_authenticated());
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let mut config = FloxConfig {
+            floxhub_token: Some(FloxhubToken::new("personal".to_string())),
+            floxmeta_user: Some("personal".to_string()),
+            profiles: BTreeMap::from([
+                ("work".to_string(), ProfileConfig {
+                    floxhub_token: Some(FloxhubToken::new("work".to_string())),
+                    floxhub_url: Some(Url::parse("https://hub.example.com").unwrap()),
+                    floxmeta_user: Some("work".to_string()),
+                }),
+                ("logged-out".to_string(), ProfileConfig::default()),
+            ]),
+            ..Default::default()
+        };
+
+        // without an active profile, the top level settings are used
+        config.apply_profile().unwrap();
+        assert_eq!(config.floxhub_token.as_ref().unwrap().as_ref(), "personal");
+
+        let personal = config.clone();
+
+        config.active_profile = Some("work".to_string());
+        config.apply_profile().unwrap();
+        assert_eq!(config.floxhub_token.as_ref().unwrap().as_ref(), "work");
+        assert_eq!(config.floxmeta_user.as_deref(), Some("work"));
+        assert_eq!(
+            config.floxhub_url.as_ref().map(Url::as_str),
+            Some("https://hub.example.com/")
+        );
+
+        // the token and user of another account are never used
+        let mut config = personal;
+        config.active_profile = Some("logged-out".to_string());
+        config.apply_profile().unwrap();
+        assert!(config.floxhub_token.is_none());
+        assert!(config.floxmeta_user.is_none());
+
+        config.active_profile = Some("missing".to_string());
+        assert!(config.apply_profile().is_err());
+
+        config.active_profile = None;
+        config
+            .profiles
+            .insert("not valid".to_string(), ProfileConfig::default());
+        assert!(config.apply_profile().is_err());
+    }
+
+    #[test]
+    fn test_floxhub_token_key() {
+        let mut config = FloxConfig::default();
+        assert_eq!(config.floxhub_token_key(), "floxhub_token");
+
+        config.active_profile = Some("work".to_string());
+        assert_eq!(config.floxhub_token_key(), "profiles.work.floxhub_token");
+    }
+
+    #[test]
+    fn test_netrc_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let netrc = tempdir.path().join("netrc");
+
+        let config = FloxConfig {
+            netrc: Some(netrc.clone()),
+            ..Default::default()
+        };
+        assert_eq!(config.netrc_file(), None);
+
+        fs::write(&netrc, "").unwrap();
+        assert_eq!(config.netrc_file(), Some(netrc));
+    }
+
+    #[test]
+    fn test_ensure_writable_cache_dir() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempdir.path().join("cache");
+
+        let mut config = FloxConfig {
+            cache_dir: cache_dir.clone(),
+            ..Default::default()
+        };
+        config.ensure_writable_cache_dir().unwrap();
+        assert_eq!(config.cache_dir, cache_dir);
+        assert!(cache_dir.is_dir());
+
+        // a cache dir below a regular file can never be created
+        let file = tempdir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let mut config = FloxConfig {
+            cache_dir: file.join("cache"),
+            ..Default::default()
+        };
+        config.ensure_writable_cache_dir().unwrap();
+        assert!(config.cache_dir.starts_with(env::temp_dir()));
+        assert!(config.cache_dir.is_dir());
+    }
+
+    #[test]
+    fn test_create_private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mode = |dir: &Path| fs::metadata(dir).unwrap().permissions().mode() & 0o777;
+
+        let dir = tempdir.path().join("private");
+        create_private_dir(&dir).unwrap();
+        assert_eq!(mode(&dir), 0o700);
+
+        // an existing directory of ours is restricted
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        create_private_dir(&dir).unwrap();
+        assert_eq!(mode(&dir), 0o700);
+
+        // a symlink could point anywhere, even if it points to a directory of ours
+        let link = tempdir.path().join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        assert!(create_private_dir(&link).is_err());
+
+        let file = tempdir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(create_private_dir(&file).is_err());
+    }
+
+    #[test]
+    fn test_process_dir() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempdir.path().join("cache");
+
+        let config = FloxConfig {
+            cache_dir: cache_dir.clone(),
+            ..Default::default()
+        };
+        assert_eq!(config.process_dir().unwrap(), cache_dir.join("process"));
+
+        let temp_dir = tempdir.path().join("temp");
+        let config = FloxConfig {
+            cache_dir,
+            temp_dir: Some(temp_dir.clone()),
+            ..Default::default()
+        };
+        assert_eq!(config.process_dir().unwrap(), temp_dir);
+        assert!(temp_dir.is_dir());
+
+        // a directory below a regular file can never be created
+        let file = tempdir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let config = FloxConfig {
+            temp_dir: Some(file.join("temp")),
+            ..Default::default()
+        };
+        assert!(config.process_dir().is_err());
+    }
+
+    #[test]
+    fn test_system() {
+        let mut config = FloxConfig::default();
+        assert_eq!(config.system().unwrap(), env!("NIX_TARGET_SYSTEM"));
+
+        config.system = Some("aarch64-darwin".to_string());
+        assert_eq!(config.system().unwrap(), "aarch64-darwin");
+
+        config.system = Some("riscv64-linux".to_string());
+        config.system().expect_err("riscv64-linux is not supported");
+
+        config.allow_unsupported_system = true;
+        assert_eq!(config.system().unwrap(), "riscv64-linux");
+    }
+
     #[test]
     fn test_writing_value() {
         let config_content = Config::write_to(
@@ -0,0 +1,191 @@
+//! Storage of the floxhub token
+//!
+//! By default the token is stored in the user's config file.
+//! If flox is built with the `keyring` feature,
+//! the token can be stored in the keychain of the OS instead,
+//! by setting `token_storage = "keyring"`.
+//! Each profile, see [super::FloxConfig::profiles], stores its own token.
+
+use flox_rust_sdk::flox::FloxhubToken;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::utils::message;
+
+/// Where the floxhub token is stored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenStorage {
+    /// The `floxhub_token` key of the user's config file
+    #[default]
+    File,
+    /// The keychain of the OS
+    Keyring,
+}
+
+#[derive(Debug, Error)]
+pub enum KeyringError {
+    #[error("flox was built without keychain support")]
+    Unsupported,
+    #[cfg(feature = "keyring")]
+    #[error(transparent)]
+    Keyring(#[from] keyring::Error),
+}
+
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "flox";
+#[cfg(feature = "keyring")]
+const KEYRING_USER: &str = "floxhub_token";
+
+/// The keychain entry holding the token of `profile`,
+/// or the token used without a profile
+#[cfg(feature = "keyring")]
+fn keyring_user(profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{KEYRING_USER}.{profile}"),
+        None => KEYRING_USER.to_string(),
+    }
+}
+
+/// Read the floxhub token of `profile` from the keychain
+///
+/// Returns [None] if no token is stored.
+pub fn read_keyring(profile: Option<&str>) -> Result<Option<FloxhubToken>, KeyringError> {
+    #[cfg(feature = "keyring")]
+    {
+        read_entry(&keyring::Entry::new(
+            KEYRING_SERVICE,
+            &keyring_user(profile),
+        )?)
+    }
+    #[cfg(not(feature = "keyring"))]
+    {
+        let _ = profile;
+        Err(KeyringError::Unsupported)
+    }
+}
+
+/// Store `token` of `profile` in the keychain,
+/// or remove the stored token if `token` is [None]
+pub fn write_keyring(
+    profile: Option<&str>,
+    token: Option<&FloxhubToken>,
+) -> Result<(), KeyringError> {
+    #[cfg(feature = "keyring")]
+    {
+        write_entry(
+            &keyring::Entry::new(KEYRING_SERVICE, &keyring_user(profile))?,
+            token,
+        )
+    }
+    #[cfg(not(feature = "keyring"))]
+    {
+        let _ = (profile, token);
+        Err(KeyringError::Unsupported)
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn read_entry(entry: &keyring::Entry) -> Result<Option<FloxhubToken>, KeyringError> {
+    match entry.get_password() {
+        Ok(token) => Ok(Some(FloxhubToken::new(token))),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn write_entry(entry: &keyring::Entry, token: Option<&FloxhubToken>) -> Result<(), KeyringError> {
+    match token {
+        Some(token) => entry.set_password(token.as_ref())?,
+        None => match entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => {},
+            Err(e) => return Err(e.into()),
+        },
+    }
+    Ok(())
+}
+
+/// Pick the token from `storage`
+///
+/// If the keychain is configured but holds no token or can't be read,
+/// `file_token` is used, e.g. a token set through `$FLOX_FLOXHUB_TOKEN`.
+pub(super) fn resolve_token(
+    storage: TokenStorage,
+    file_token: Option<&FloxhubToken>,
+    read_keyring: impl FnOnce() -> Result<Option<FloxhubToken>, KeyringError>,
+) -> Option<FloxhubToken> {
+    if storage == TokenStorage::Keyring {
+        match read_keyring() {
+            Ok(Some(token)) => return Some(token),
+            Ok(None) => {},
+            Err(e) => message::warning(format!(
+                "Could not read the floxhub token from the keychain, using the config file instead: {e}"
+            )),
+        }
+    }
+    file_token.cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(token: &str) -> FloxhubToken {
+        FloxhubToken::new(token.to_string())
+    }
+
+    fn secret(token: &Option<FloxhubToken>) -> Option<&str> {
+        token.as_ref().map(|token| token.as_ref())
+    }
+
+    #[test]
+    fn file_storage_ignores_keyring() {
+        let resolved = resolve_token(TokenStorage::File, Some(&token("file")), || {
+            panic!("keychain should not be read")
+        });
+        assert_eq!(secret(&resolved), Some("file"));
+    }
+
+    #[test]
+    fn keyring_storage_falls_back_to_file() {
+        let resolved = resolve_token(TokenStorage::Keyring, Some(&token("file")), || {
+            Ok(Some(token("keyring")))
+        });
+        assert_eq!(secret(&resolved), Some("keyring"));
+
+        let resolved = resolve_token(TokenStorage::Keyring, Some(&token("file")), || Ok(None));
+        assert_eq!(secret(&resolved), Some("file"));
+
+        let resolved = resolve_token(TokenStorage::Keyring, Some(&token("file")), || {
+            Err(KeyringError::Unsupported)
+        });
+        assert_eq!(secret(&resolved), Some("file"));
+    }
+
+    #[cfg(feature = "keyring")]
+    #[test]
+    fn keyring_user_per_profile() {
+        assert_eq!(keyring_user(None), "floxhub_token");
+        assert_eq!(keyring_user(Some("work")), "floxhub_token.work");
+    }
+
+    #[cfg(feature = "keyring")]
+    #[test]
+    fn keyring_round_trip() {
+        keyring::set_default_credential_builder(keyring::mock::default_credential_builder());
+        // mock credentials are only shared within a single entry
+        let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).unwrap();
+
+        assert!(read_entry(&entry).unwrap().is_none());
+
+        write_entry(&entry, Some(&token("secret"))).unwrap();
+        let stored = read_entry(&entry).unwrap();
+        assert_eq!(secret(&stored), Some("secret"));
+
+        write_entry(&entry, None).unwrap();
+        assert!(read_entry(&entry).unwrap().is_none());
+        // removing a missing token is not an error
+        write_entry(&entry, None).unwrap();
+    }
+}
@@ -24,6 +24,7 @@ async fn run(args: FloxArgs) -> Result<()> {
     init_logger(Some(args.verbosity.clone()));
     set_user()?;
     set_parent_process_id();
+    config::Config::set_cli_overrides(&args.config_options)?;
     let config = config::Config::parse()?;
     init_global_manifest(&config.flox.config_dir.join("global-manifest.toml"))?;
     args.handle(config).await?;
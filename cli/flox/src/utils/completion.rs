@@ -1,6 +1,7 @@
 use anyhow::{bail, Result};
 use async_trait::async_trait;
 use flox_rust_sdk::flox::{Flox, Floxhub, DEFAULT_FLOXHUB_URL};
+use flox_rust_sdk::utils::clock::system_clock;
 use log::debug;
 use tempfile::TempDir;
 
@@ -20,17 +21,12 @@ where
 #[async_trait]
 impl FloxCompletionExt for Flox {
     fn completion_instance() -> Result<Flox> {
-        let config = Config::parse()
+        let mut config = Config::parse()
             .map_err(|e| debug!("Failed to load config: {e}"))
             .unwrap();
+        config.flox.ensure_writable_cache_dir()?;
 
-        let process_dir = config.flox.cache_dir.join("process");
-        match std::fs::create_dir_all(&process_dir) {
-            Ok(_) => {},
-            Err(e) => {
-                bail!("Failed to create process dir: {e}");
-            },
-        };
+        let process_dir = config.flox.process_dir()?;
 
         let temp_dir = match TempDir::new_in(process_dir) {
             Ok(x) => x,
@@ -48,21 +44,26 @@ impl FloxCompletionExt for Flox {
         .map_err(|e| debug!("Failed to initialize access tokens: {e}"))
         .unwrap_or_default();
 
-        let netrc_file = dirs::home_dir()
-            .expect("User must have a home directory")
-            .join(".netrc");
+        let system = config.flox.system()?;
+        let netrc_file = config.flox.netrc_file();
+        let git_timeout = config.flox.git_timeout();
+        let floxhub_token = config.flox.stored_floxhub_token();
 
         Ok(Flox {
             cache_dir: config.flox.cache_dir,
             data_dir: config.flox.data_dir,
             config_dir: config.flox.config_dir,
             temp_dir: temp_dir.into_path(),
-            system: env!("NIX_TARGET_SYSTEM").to_string(),
+            system,
             netrc_file,
             access_tokens,
             uuid: uuid::Uuid::nil(),
-            floxhub_token: config.flox.floxhub_token,
+            floxhub_token,
             floxhub: Floxhub::new(DEFAULT_FLOXHUB_URL.clone(), None)?,
+            floxmeta_reference: config.flox.floxmeta_reference,
+            git_timeout,
+            fetch_jobs: config.flox.fetch_jobs,
+            clock: system_clock(),
         })
     }
 }